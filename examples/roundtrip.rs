@@ -9,7 +9,7 @@
 
 extern crate xml;
 use std::fs::File;
-use std::io::Read;
+use xml::ReaderError;
 
 fn main() {
     let mut args = std::env::args();
@@ -21,7 +21,7 @@ fn main() {
         println!("Usage: {} <file>", name);
         return;
     };
-    let mut rdr = match File::open(path) {
+    let rdr = match File::open(path) {
         Ok(file) => file,
         Err(err) => {
             println!("Couldn't open file: {}", err);
@@ -29,21 +29,23 @@ fn main() {
         }
     };
 
-    let mut p = xml::Parser::new();
+    let p = xml::EventReader::new(rdr);
     let mut e = xml::ElementBuilder::new();
 
-    let mut string = String::new();
-    if let Err(err) = rdr.read_to_string(&mut string) {
-        println!("Reading failed: {}", err);
-        std::process::exit(1);
-    };
-
-    p.feed_str(&string);
-    for event in p.filter_map(|x| e.handle_event(x)) {
+    for event in p {
         // println!("{:?}", event);
-        match event {
-            Ok(e) => println!("{}", e),
-            Err(e) => println!("{}", e),
+        let event = match event {
+            Ok(event) => Ok(event),
+            Err(ReaderError::Parser(err)) => Err(err),
+            Err(ReaderError::Io(err)) => {
+                println!("Reading failed: {}", err);
+                std::process::exit(1);
+            }
+        };
+        match e.handle_event(event) {
+            Some(Ok(e)) => println!("{}", e),
+            Some(Err(e)) => println!("{}", e),
+            None => (),
         }
     }
 }
@@ -0,0 +1,269 @@
+// RustyXML
+// Copyright 2013-2016 RustyXML developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::entity::{DEFAULT_MAX_DEPTH, DEFAULT_MAX_EXPANSION};
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Options for a `Parser`, passed to `Parser::with_config()`
+///
+/// Options are set using the builder methods, all of which are disabled by default
+/// except for `normalization()` and `namespace_aware()`. No limits are imposed on the input
+/// by default, except for the expansion of entities.
+/// When parsing untrusted input they should be set, so it cannot exhaust memory,
+/// see `max_buffer_size()` for how to feed such input.
+///
+/// ~~~
+/// use xml::{Event, Parser, ParserConfig};
+///
+/// let config = ParserConfig::new()
+///     .trim_whitespace(true)
+///     .coalesce_characters(true)
+///     .skip_comments(true);
+/// let mut p = Parser::with_config(config);
+/// p.feed_str("<a>\n  Rust <!-- skipped --><![CDATA[& XML]]>\n</a>");
+/// let events: Vec<Event> = p.map(|e| e.unwrap()).collect();
+/// assert_eq!(events.len(), 3);
+/// assert_eq!(events[1], Event::Characters("Rust & XML".to_owned()));
+/// ~~~
+pub struct ParserConfig {
+    pub(crate) strict: bool,
+    pub(crate) lenient: bool,
+    pub(crate) normalize: bool,
+    pub(crate) namespace_aware: bool,
+    pub(crate) strip_namespace_declarations: bool,
+    pub(crate) concatenated_documents: bool,
+    // Initial namespace bindings as (prefix, namespace) pairs, the default namespace has prefix ""
+    pub(crate) namespaces: Vec<(String, String)>,
+    pub(crate) trim_whitespace: bool,
+    pub(crate) ignore_whitespace: bool,
+    pub(crate) coalesce_characters: bool,
+    pub(crate) skip_comments: bool,
+    pub(crate) skip_processing_instructions: bool,
+    pub(crate) max_entity_depth: usize,
+    pub(crate) max_entity_expansion: usize,
+    pub(crate) max_depth: usize,
+    pub(crate) max_attributes: usize,
+    pub(crate) max_name_length: usize,
+    pub(crate) max_attribute_value_size: usize,
+    pub(crate) max_text_size: usize,
+    pub(crate) max_buffer_size: usize,
+}
+
+impl Default for ParserConfig {
+    fn default() -> ParserConfig {
+        ParserConfig::new()
+    }
+}
+
+impl ParserConfig {
+    /// Returns the default configuration
+    pub fn new() -> ParserConfig {
+        ParserConfig {
+            strict: false,
+            lenient: false,
+            normalize: true,
+            namespace_aware: true,
+            strip_namespace_declarations: false,
+            concatenated_documents: false,
+            namespaces: Vec::new(),
+            trim_whitespace: false,
+            ignore_whitespace: false,
+            coalesce_characters: false,
+            skip_comments: false,
+            skip_processing_instructions: false,
+            max_entity_depth: DEFAULT_MAX_DEPTH,
+            max_entity_expansion: DEFAULT_MAX_EXPANSION,
+            max_depth: std::usize::MAX,
+            max_attributes: std::usize::MAX,
+            max_name_length: std::usize::MAX,
+            max_attribute_value_size: std::usize::MAX,
+            max_text_size: std::usize::MAX,
+            max_buffer_size: std::usize::MAX,
+        }
+    }
+
+    /// Enables strict well-formedness checks, see `Parser::set_strict()`
+    pub fn strict(mut self, strict: bool) -> ParserConfig {
+        self.strict = strict;
+        self
+    }
+
+    /// Enables recovering from errors, see `Parser::set_lenient()`
+    pub fn lenient(mut self, lenient: bool) -> ParserConfig {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Enables the normalization of line breaks and attribute values,
+    /// see `Parser::set_normalization()`
+    pub fn normalization(mut self, normalize: bool) -> ParserConfig {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Enables resolving namespace prefixes
+    ///
+    /// When disabled, element and attribute names are reported as written, including any prefix,
+    /// with `ns` and `prefix` set to `None`. Namespace declarations are reported as
+    /// ordinary attributes, and prefixes do not need to be declared.
+    pub fn namespace_aware(mut self, aware: bool) -> ParserConfig {
+        self.namespace_aware = aware;
+        self
+    }
+
+    /// Treats the input as a sequence of documents, as found in log files
+    ///
+    /// An `Event::DocumentEnd` is produced after the root element of each document has been
+    /// closed. Anything following it is parsed as a new document, which may start with
+    /// an XML declaration preceded by whitespace. Comments and processing instructions
+    /// following a root element are reported as part of the next document.
+    pub fn concatenated_documents(mut self, concatenated: bool) -> ParserConfig {
+        self.concatenated_documents = concatenated;
+        self
+    }
+
+    /// Binds a prefix to a namespace outside of the document
+    ///
+    /// This allows parsing a fragment in the context of an enclosing document.
+    /// Only the predefined `xml` and `xmlns` prefixes are bound by default.
+    /// Bindings are ignored if `namespace_aware()` is disabled.
+    ///
+    /// ~~~
+    /// use xml::{Event, Parser, ParserConfig};
+    ///
+    /// let config = ParserConfig::new()
+    ///     .define_prefix("stream".to_owned(), "http://etherx.jabber.org/streams".to_owned())
+    ///     .default_ns("jabber:client".to_owned());
+    /// let mut p = Parser::with_config(config);
+    /// p.feed_str("<stream:features><bind/></stream:features>");
+    /// match p.nth(1) {
+    ///     Some(Ok(Event::ElementStart(tag))) => assert_eq!(tag.ns.unwrap(), "jabber:client"),
+    ///     _ => panic!(),
+    /// }
+    /// ~~~
+    pub fn define_prefix(mut self, prefix: String, ns: String) -> ParserConfig {
+        self.namespaces.push((prefix, ns));
+        self
+    }
+
+    /// Sets the default namespace outside of the document, see `define_prefix()`
+    pub fn default_ns(mut self, ns: String) -> ParserConfig {
+        self.namespaces.push((String::new(), ns));
+        self
+    }
+
+    /// Removes namespace declarations from the `attributes` of `StartTag`s
+    ///
+    /// They are still reported in the `namespaces` field.
+    pub fn strip_namespace_declarations(mut self, strip: bool) -> ParserConfig {
+        self.strip_namespace_declarations = strip;
+        self
+    }
+
+    /// Removes leading and trailing whitespace from `Characters` events,
+    /// dropping those left empty
+    pub fn trim_whitespace(mut self, trim: bool) -> ParserConfig {
+        self.trim_whitespace = trim;
+        self
+    }
+
+    /// Drops `Characters` events consisting of whitespace only
+    pub fn ignore_whitespace(mut self, ignore: bool) -> ParserConfig {
+        self.ignore_whitespace = ignore;
+        self
+    }
+
+    /// Merges adjacent character data and CDATA sections into a single `Characters` event
+    ///
+    /// Skipped comments and processing instructions do not separate character data.
+    /// Whitespace is trimmed or ignored after merging.
+    pub fn coalesce_characters(mut self, coalesce: bool) -> ParserConfig {
+        self.coalesce_characters = coalesce;
+        self
+    }
+
+    /// Drops `Comment` events
+    pub fn skip_comments(mut self, skip: bool) -> ParserConfig {
+        self.skip_comments = skip;
+        self
+    }
+
+    /// Drops `PI` events, the XML declaration is still reported
+    pub fn skip_processing_instructions(mut self, skip: bool) -> ParserConfig {
+        self.skip_processing_instructions = skip;
+        self
+    }
+
+    /// Sets the maximum nesting depth of entity references,
+    /// see `Parser::set_max_entity_depth()`
+    pub fn max_entity_depth(mut self, depth: usize) -> ParserConfig {
+        self.max_entity_depth = depth;
+        self
+    }
+
+    /// Sets the maximum size of expanded entities,
+    /// see `Parser::set_max_entity_expansion()`
+    pub fn max_entity_expansion(mut self, size: usize) -> ParserConfig {
+        self.max_entity_expansion = size;
+        self
+    }
+
+    /// Sets the maximum number of elements open at once
+    ///
+    /// Exceeding it is reported as `ParserErrorKind::NestingTooDeep`.
+    pub fn max_depth(mut self, depth: usize) -> ParserConfig {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Sets the maximum number of attributes of an element, including namespace declarations
+    ///
+    /// Exceeding it is reported as `ParserErrorKind::TooManyAttributes`.
+    pub fn max_attributes(mut self, count: usize) -> ParserConfig {
+        self.max_attributes = count;
+        self
+    }
+
+    /// Sets the maximum length in bytes of element and attribute names, including any prefix
+    ///
+    /// Exceeding it is reported as `ParserErrorKind::NameTooLong`.
+    pub fn max_name_length(mut self, length: usize) -> ParserConfig {
+        self.max_name_length = length;
+        self
+    }
+
+    /// Sets the maximum size in bytes of an attribute value, as written
+    ///
+    /// Exceeding it is reported as `ParserErrorKind::AttributeValueTooLarge`.
+    pub fn max_attribute_value_size(mut self, size: usize) -> ParserConfig {
+        self.max_attribute_value_size = size;
+        self
+    }
+
+    /// Sets the maximum size in bytes of a run of character data or a CDATA section, as written
+    ///
+    /// Exceeding it is reported as `ParserErrorKind::TextTooLarge`.
+    pub fn max_text_size(mut self, size: usize) -> ParserConfig {
+        self.max_text_size = size;
+        self
+    }
+
+    /// Sets the maximum size in bytes of the input buffered for a single construct,
+    /// such as a tag, comment or run of character data
+    ///
+    /// Input fed to the parser is kept until the construct it belongs to is complete,
+    /// input not parsed yet counts towards the limit as well. Exceeding the limit is reported
+    /// as `ParserErrorKind::InputBufferTooLarge`, input that would exceed it is rejected
+    /// by `Parser::feed_str()` and `Parser::feed_bytes()`. Larger documents have to be fed
+    /// in chunks, iterating over the parser after each one, as `EventReader` does.
+    pub fn max_buffer_size(mut self, size: usize) -> ParserConfig {
+        self.max_buffer_size = size;
+        self
+    }
+}
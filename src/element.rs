@@ -9,13 +9,14 @@
 
 use crate::element_builder::{BuilderError, ElementBuilder};
 use crate::parser::Parser;
-use crate::{escape, AttrMap, Xml};
+use crate::{escape, AttrMap, ProcessingInstruction, Xml};
 
 use std::collections::HashMap;
 use std::fmt;
 use std::iter::IntoIterator;
 use std::slice;
 use std::str::FromStr;
+use std::sync::Arc;
 
 #[derive(Clone, PartialEq, Debug)]
 /// A struct representing an XML element
@@ -32,6 +33,9 @@ pub struct Element {
     pub(crate) prefixes: HashMap<String, String>,
     /// The element's default namespace
     pub(crate) default_ns: Option<String>,
+    /// The namespaces bound to prefixes in scope, by prefix.
+    /// Shared with the parent unless the element declares prefixes itself.
+    pub(crate) namespaces: Arc<HashMap<String, String>>,
 }
 
 fn fmt_elem(
@@ -41,7 +45,7 @@ fn fmt_elem(
     f: &mut fmt::Formatter,
 ) -> fmt::Result {
     let mut all_prefixes = all_prefixes.clone();
-    all_prefixes.extend(elem.prefixes.clone().into_iter());
+    all_prefixes.extend(elem.prefixes.clone());
 
     // Do we need a prefix?
     if elem.ns != elem.default_ns {
@@ -54,14 +58,10 @@ fn fmt_elem(
     }
 
     // Do we need to set the default namespace ?
-    if !elem
-        .attributes
-        .iter()
-        .any(|(&(ref name, _), _)| name == "xmlns")
-    {
+    if !elem.attributes.iter().any(|((name, _), _)| name == "xmlns") {
         match (parent, &elem.default_ns) {
             // No parent, namespace is not empty
-            (None, &Some(ref ns)) => write!(f, " xmlns='{}'", *ns)?,
+            (None, Some(ns)) => write!(f, " xmlns='{}'", *ns)?,
             // Parent and child namespace differ
             (Some(parent), ns) if parent.default_ns != *ns => {
                 write!(f, " xmlns='{}'", ns.as_ref().map_or("", |x| &x[..]))?
@@ -70,7 +70,7 @@ fn fmt_elem(
         }
     }
 
-    for (&(ref name, ref ns), value) in &elem.attributes {
+    for ((name, ns), value) in &elem.attributes {
         match *ns {
             Some(ref ns) => {
                 let prefix = all_prefixes.get(ns).expect("No namespace prefix bound");
@@ -148,6 +148,12 @@ impl Element {
             "http://www.w3.org/2000/xmlns/".to_owned(),
             "xmlns".to_owned(),
         );
+        let namespaces = Arc::new(
+            prefixes
+                .iter()
+                .map(|(ns, prefix)| (prefix.clone(), ns.clone()))
+                .collect(),
+        );
 
         let attributes: AttrMap<_, _> = attrs
             .into_iter()
@@ -159,11 +165,49 @@ impl Element {
             ns: ns.clone(),
             default_ns: ns,
             prefixes,
+            namespaces,
             attributes,
             children: Vec::new(),
         }
     }
 
+    /// Returns the namespace bound to a prefix in this element,
+    /// or its default namespace if `prefix` is `None`
+    ///
+    /// Elements built by an `ElementBuilder` know the bindings declared on them
+    /// and their ancestors, as well as the prefixes defined on the builder,
+    /// see `ElementBuilder::with_config()`.
+    ///
+    /// ~~~
+    /// use xml::Element;
+    ///
+    /// let elem: Element = "<a xmlns:foo='urn:foo'><b/></a>".parse().unwrap();
+    /// let child = elem.get_child("b", None).unwrap();
+    /// assert_eq!(child.namespace_for_prefix(Some("foo")), Some("urn:foo"));
+    /// assert_eq!(child.namespace_for_prefix(None), None);
+    /// ~~~
+    pub fn namespace_for_prefix(&self, prefix: Option<&str>) -> Option<&str> {
+        match prefix {
+            None => self.default_ns.as_deref(),
+            Some(prefix) => self.namespaces.get(prefix).map(|ns| &ns[..]),
+        }
+    }
+
+    /// Returns the namespace bindings in scope in this element as (prefix, namespace) pairs,
+    /// sorted by prefix, see `namespace_for_prefix()`
+    pub fn in_scope_namespaces(&self) -> Vec<(Option<&str>, &str)> {
+        let mut bindings: Vec<(Option<&str>, &str)> = self
+            .namespaces
+            .iter()
+            .map(|(prefix, ns)| (Some(&prefix[..]), &ns[..]))
+            .collect();
+        if let Some(ref ns) = self.default_ns {
+            bindings.push((None, ns));
+        }
+        bindings.sort();
+        bindings
+    }
+
     /// Returns the character and CDATA contained in the element.
     pub fn content_str(&self) -> String {
         let mut res = String::new();
@@ -256,9 +300,11 @@ impl Element {
         self
     }
 
-    /// Appends processing information. Returns a mutable reference to self.
-    pub fn pi(&mut self, text: String) -> &mut Element {
-        self.children.push(Xml::PINode(text));
+    /// Appends processing information with the given target and data.
+    /// Returns a mutable reference to self.
+    pub fn pi(&mut self, target: String, data: String) -> &mut Element {
+        self.children
+            .push(Xml::PINode(ProcessingInstruction { target, data }));
         self
     }
 }
@@ -271,6 +317,7 @@ impl FromStr for Element {
         let mut e = ElementBuilder::new();
 
         p.feed_str(data);
+        p.finish();
         p.find_map(|x| e.handle_event(x))
             .unwrap_or(Err(BuilderError::NoElement))
     }
@@ -279,6 +326,7 @@ impl FromStr for Element {
 #[cfg(test)]
 mod tests {
     use super::Element;
+    use crate::{ElementBuilder, Parser, ParserConfig};
 
     #[test]
     fn test_get_children() {
@@ -343,4 +391,69 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_namespace_for_prefix() {
+        let elem: Element = "<a xmlns='urn:a' xmlns:b='urn:b'><c xmlns:b='urn:c'/></a>"
+            .parse()
+            .unwrap();
+        assert_eq!(elem.namespace_for_prefix(None), Some("urn:a"));
+        assert_eq!(elem.namespace_for_prefix(Some("b")), Some("urn:b"));
+        assert_eq!(elem.namespace_for_prefix(Some("c")), None);
+
+        let child = elem.get_child("c", Some("urn:a")).unwrap();
+        assert_eq!(child.namespace_for_prefix(None), Some("urn:a"));
+        assert_eq!(child.namespace_for_prefix(Some("b")), Some("urn:c"));
+        assert_eq!(
+            child.in_scope_namespaces(),
+            vec![
+                (None, "urn:a"),
+                (Some("b"), "urn:c"),
+                (Some("xml"), "http://www.w3.org/XML/1998/namespace"),
+                (Some("xmlns"), "http://www.w3.org/2000/xmlns/"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_namespace_for_prefix_shared() {
+        let elem: Element = "<a xmlns:x='urn' xmlns:y='urn'><b xmlns:z='urn'/></a>"
+            .parse()
+            .unwrap();
+        assert_eq!(elem.namespace_for_prefix(Some("x")), Some("urn"));
+        assert_eq!(elem.namespace_for_prefix(Some("y")), Some("urn"));
+
+        let child = elem.get_child("b", None).unwrap();
+        let bindings: Vec<_> = child
+            .in_scope_namespaces()
+            .into_iter()
+            .filter(|&(_, ns)| ns == "urn")
+            .collect();
+        assert_eq!(
+            bindings,
+            vec![(Some("x"), "urn"), (Some("y"), "urn"), (Some("z"), "urn")]
+        );
+    }
+
+    #[test]
+    fn test_namespace_for_prefix_config() {
+        let config = ParserConfig::new()
+            .define_prefix("stream".to_owned(), "urn:stream".to_owned())
+            .default_ns("urn:client".to_owned());
+        let mut p = Parser::with_config(config.clone());
+        let mut builder = ElementBuilder::with_config(&config);
+        p.feed_str("<stream:features><bind/></stream:features>");
+        let elem = p
+            .filter_map(|e| builder.handle_event(e))
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(elem.ns, Some("urn:stream".to_owned()));
+        let child = elem.get_child("bind", Some("urn:client")).unwrap();
+        assert_eq!(
+            child.namespace_for_prefix(Some("stream")),
+            Some("urn:stream")
+        );
+        assert_eq!(child.namespace_for_prefix(None), Some("urn:client"));
+    }
 }
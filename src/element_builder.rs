@@ -8,10 +8,11 @@
 // except according to those terms.
 
 use crate::parser::ParserError;
-use crate::{Element, EndTag, Event, StartTag, Xml};
+use crate::{Doctype, Element, EndTag, Event, ParserConfig, StartTag, Xml};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+use std::sync::Arc;
 
 #[derive(PartialEq, Debug, Clone)]
 /// The structure returned for errors encountered while building an `Element`
@@ -66,10 +67,13 @@ pub struct ElementBuilder {
     stack: Vec<Element>,
     default_ns: Vec<Option<String>>,
     prefixes: HashMap<String, String>,
+    namespaces: Arc<HashMap<String, String>>,
+    doctype: Option<Doctype>,
 }
 
 impl ElementBuilder {
     /// Returns a new `ElementBuilder`
+    #[allow(clippy::new_without_default)]
     pub fn new() -> ElementBuilder {
         let mut prefixes = HashMap::with_capacity(2);
         prefixes.insert(
@@ -80,15 +84,43 @@ impl ElementBuilder {
             "http://www.w3.org/2000/xmlns/".to_owned(),
             "xmlns".to_owned(),
         );
+        let namespaces = Arc::new(
+            prefixes
+                .iter()
+                .map(|(ns, prefix)| (prefix.clone(), ns.clone()))
+                .collect(),
+        );
         ElementBuilder {
             stack: Vec::new(),
             default_ns: Vec::new(),
             prefixes,
+            namespaces,
+            doctype: None,
+        }
+    }
+
+    /// Returns a new `ElementBuilder` for events of a `Parser` using the given configuration
+    ///
+    /// The prefixes and default namespace bound by the configuration are set on the builder,
+    /// unless namespace processing is disabled.
+    pub fn with_config(config: &ParserConfig) -> ElementBuilder {
+        let mut builder = ElementBuilder::new();
+        if !config.namespace_aware {
+            return builder;
+        }
+        for (prefix, ns) in &config.namespaces {
+            if prefix.is_empty() {
+                builder.set_default_ns(ns.clone());
+            } else {
+                builder.define_prefix(prefix.clone(), ns.clone());
+            }
         }
+        builder
     }
 
     /// Bind a prefix to a namespace
     pub fn define_prefix(&mut self, prefix: String, ns: String) {
+        Arc::make_mut(&mut self.namespaces).insert(prefix.clone(), ns.clone());
         self.prefixes.insert(ns, prefix);
     }
 
@@ -97,6 +129,23 @@ impl ElementBuilder {
         self.default_ns = vec![Some(ns)];
     }
 
+    /// Discards the elements being built and the document type declaration,
+    /// to build a new document after `Parser::reset()`
+    ///
+    /// Prefixes and the default namespace set on the builder are kept.
+    pub fn reset(&mut self) {
+        // Each open element pushed one default namespace, or at most one if none was set
+        let len = self.default_ns.len().saturating_sub(self.stack.len());
+        self.default_ns.truncate(len);
+        self.stack.clear();
+        self.doctype = None;
+    }
+
+    /// Returns the document type declaration encountered, if any
+    pub fn doctype(&self) -> Option<&Doctype> {
+        self.doctype.as_ref()
+    }
+
     /// Let the builder process an `Event` to ultimately build an `Element`.
     ///
     /// While no root element has been finished `None` is returned.
@@ -111,9 +160,10 @@ impl ElementBuilder {
             Err(e) => return Some(Err(From::from(e))),
         };
         match e {
-            Event::PI(cont) => {
+            Event::XmlDecl(_) => (),
+            Event::PI(pi) => {
                 if let Some(elem) = self.stack.last_mut() {
-                    elem.children.push(Xml::PINode(cont));
+                    elem.children.push(Xml::PINode(pi));
                 }
             }
             Event::ElementStart(StartTag {
@@ -121,12 +171,17 @@ impl ElementBuilder {
                 ns,
                 prefix: _,
                 attributes,
+                namespaces,
             }) => {
                 let mut elem = Element {
                     name,
                     ns,
                     default_ns: None,
                     prefixes: self.prefixes.clone(),
+                    namespaces: match self.stack.last() {
+                        Some(parent) => parent.namespaces.clone(),
+                        None => self.namespaces.clone(),
+                    },
                     attributes,
                     children: Vec::new(),
                 };
@@ -135,22 +190,24 @@ impl ElementBuilder {
                     self.default_ns.push(default)
                 }
 
-                for (&(ref name, ref ns), value) in &elem.attributes {
-                    if ns.is_none() && name == "xmlns" {
-                        self.default_ns.pop();
-                        if value.is_empty() {
-                            self.default_ns.push(None);
-                        } else {
-                            self.default_ns.push(Some(value.clone()));
+                for (prefix, ns) in namespaces {
+                    match prefix {
+                        None => {
+                            self.default_ns.pop();
+                            if ns.is_empty() {
+                                self.default_ns.push(None);
+                            } else {
+                                self.default_ns.push(Some(ns));
+                            }
+                        }
+                        // An empty namespace undeclares the prefix
+                        Some(prefix) if ns.is_empty() => {
+                            Arc::make_mut(&mut elem.namespaces).remove(&prefix);
+                        }
+                        Some(prefix) => {
+                            Arc::make_mut(&mut elem.namespaces).insert(prefix.clone(), ns.clone());
+                            elem.prefixes.insert(ns, prefix);
                         }
-                        continue;
-                    }
-
-                    if ns
-                        .as_ref()
-                        .map_or(false, |x| x == "http://www.w3.org/2000/xmlns/")
-                    {
-                        elem.prefixes.insert(value.clone(), name.clone());
                     }
                 }
                 elem.default_ns = self.default_ns.last().unwrap_or(&None).clone();
@@ -191,6 +248,8 @@ impl ElementBuilder {
                     elem.children.push(Xml::CommentNode(cont));
                 }
             }
+            Event::Doctype(doctype) => self.doctype = Some(doctype),
+            Event::DocumentEnd => (),
         }
         None
     }
@@ -0,0 +1,371 @@
+// RustyXML
+// Copyright 2013-2016 RustyXML developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::parser::ParserErrorKind;
+use std::char;
+use std::mem;
+use std::str;
+
+// Give up looking for an encoding declaration after this many bytes
+const MAX_DECL_LEN: usize = 1024;
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+/// Character encodings the `Parser` can decode
+pub enum Encoding {
+    /// UTF-8
+    Utf8,
+    /// UTF-16, little endian
+    Utf16Le,
+    /// UTF-16, big endian
+    Utf16Be,
+    /// ISO-8859-1 (Latin-1)
+    Latin1,
+}
+
+impl Encoding {
+    /// Looks up an encoding by the name used in an XML declaration.
+    /// Names are matched case-insensitively.
+    pub fn from_label(label: &str) -> Option<Encoding> {
+        let label = label.to_ascii_lowercase();
+        match &label[..] {
+            "utf-8" | "utf8" | "us-ascii" | "ascii" => Some(Encoding::Utf8),
+            "utf-16le" => Some(Encoding::Utf16Le),
+            "utf-16be" => Some(Encoding::Utf16Be),
+            "iso-8859-1" | "iso8859-1" | "iso_8859-1" | "latin1" | "l1" | "iso-ir-100"
+            | "cp819" | "ibm819" | "csisolatin1" => Some(Encoding::Latin1),
+            _ => None,
+        }
+    }
+}
+
+// Result of inspecting the first bytes of a document, following XML 1.0 Appendix F
+enum Sniffed {
+    // An encoding was determined, the given number of bytes are a byte order mark
+    Encoding(Encoding, usize),
+    // ASCII compatible, the encoding declaration has to be consulted
+    Declaration,
+}
+
+fn sniff(data: &[u8]) -> Option<Sniffed> {
+    static PATTERNS: [(&[u8], Option<Encoding>, usize); 6] = [
+        (&[0xEF, 0xBB, 0xBF], Some(Encoding::Utf8), 3),
+        (&[0xFF, 0xFE], Some(Encoding::Utf16Le), 2),
+        (&[0xFE, 0xFF], Some(Encoding::Utf16Be), 2),
+        (&[0x3C, 0x00, 0x3F, 0x00], Some(Encoding::Utf16Le), 0),
+        (&[0x00, 0x3C, 0x00, 0x3F], Some(Encoding::Utf16Be), 0),
+        (b"<?xm", None, 0),
+    ];
+
+    let mut undecided = false;
+    for &(pattern, encoding, bom) in PATTERNS.iter() {
+        if data.starts_with(pattern) {
+            return Some(match encoding {
+                Some(encoding) => Sniffed::Encoding(encoding, bom),
+                None => Sniffed::Declaration,
+            });
+        }
+        undecided |= pattern.starts_with(data);
+    }
+
+    if undecided {
+        None
+    } else {
+        Some(Sniffed::Encoding(Encoding::Utf8, 0))
+    }
+}
+
+// Extracts the value of the encoding pseudo-attribute from an XML declaration
+fn declared_encoding(decl: &[u8]) -> Option<&[u8]> {
+    let pos = decl.windows(8).position(|w| w == b"encoding")?;
+    let rest = &decl[pos + 8..];
+    let rest = &rest[rest.iter().position(|b| !b.is_ascii_whitespace())?..];
+    if rest.first() != Some(&b'=') {
+        return None;
+    }
+    let rest = &rest[1..];
+    let rest = &rest[rest.iter().position(|b| !b.is_ascii_whitespace())?..];
+    let delim = match rest.first() {
+        Some(&d) if d == b'"' || d == b'\'' => d,
+        _ => return None,
+    };
+    let rest = &rest[1..];
+    let end = rest.iter().position(|&b| b == delim)?;
+    Some(&rest[..end])
+}
+
+/// Incrementally decodes bytes to characters
+pub(crate) struct Decoder {
+    encoding: Option<Encoding>,
+    // Bytes that could not be decoded yet
+    pending: Vec<u8>,
+    error: Option<ParserErrorKind>,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            encoding: None,
+            pending: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// The encoding in use, once it has been determined
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.encoding
+    }
+
+    /// The error encountered while decoding, if any
+    pub fn error(&self) -> Option<ParserErrorKind> {
+        self.error.clone()
+    }
+
+    /// Decodes as much of `data` as possible, appending the result to `out`.
+    /// Undecodable trailing bytes are kept until the next call.
+    pub fn decode(&mut self, data: &[u8], out: &mut String) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let encoding = match self.encoding {
+            Some(encoding) => encoding,
+            None => {
+                self.pending.extend_from_slice(data);
+                if let Some(encoding) = self.detect() {
+                    self.encoding = Some(encoding);
+                    let pending = mem::take(&mut self.pending);
+                    self.decode(&pending, out);
+                }
+                return;
+            }
+        };
+
+        match encoding {
+            Encoding::Utf8 => self.decode_utf8(data, out),
+            Encoding::Utf16Le => self.decode_utf16(data, out, u16::from_le_bytes),
+            Encoding::Utf16Be => self.decode_utf16(data, out, u16::from_be_bytes),
+            Encoding::Latin1 => out.extend(data.iter().map(|&b| char::from(b))),
+        }
+    }
+
+    /// Decodes the remaining bytes once all data has been passed to `decode()`.
+    /// An incomplete character at the end of the data is an error.
+    pub fn finish(&mut self, out: &mut String) {
+        if self.error.is_some() {
+            return;
+        }
+        if self.encoding.is_none() {
+            // Too little data to detect anything else
+            self.encoding = Some(Encoding::Utf8);
+            let pending = mem::take(&mut self.pending);
+            self.decode(&pending, out);
+        }
+        if !self.pending.is_empty() {
+            self.error = match self.encoding {
+                Some(Encoding::Utf16Le) | Some(Encoding::Utf16Be) => {
+                    Some(ParserErrorKind::InvalidUtf16)
+                }
+                _ => Some(ParserErrorKind::InvalidUtf8),
+            };
+        }
+    }
+
+    // Tries to determine the encoding from the pending bytes, stripping any byte order mark
+    fn detect(&mut self) -> Option<Encoding> {
+        match sniff(&self.pending)? {
+            Sniffed::Encoding(encoding, bom) => {
+                self.pending.drain(..bom);
+                Some(encoding)
+            }
+            Sniffed::Declaration => {
+                let end = match self.pending.iter().position(|&b| b == b'>') {
+                    Some(end) => end,
+                    None if self.pending.len() > MAX_DECL_LEN => return Some(Encoding::Utf8),
+                    None => return None,
+                };
+                let label = match declared_encoding(&self.pending[..end]) {
+                    Some(label) => label,
+                    None => return Some(Encoding::Utf8),
+                };
+                let encoding = str::from_utf8(label).ok().and_then(Encoding::from_label);
+                match encoding {
+                    // The document is ASCII compatible, UTF-16 has to be announced by a BOM
+                    Some(Encoding::Utf16Le) | Some(Encoding::Utf16Be) | None => {
+                        self.error = Some(ParserErrorKind::UnsupportedEncoding);
+                        None
+                    }
+                    encoding => encoding,
+                }
+            }
+        }
+    }
+
+    fn decode_utf8(&mut self, mut data: &[u8], out: &mut String) {
+        // Complete a character left over from the previous call
+        while !self.pending.is_empty() {
+            let (&b, rest) = match data.split_first() {
+                Some(split) => split,
+                None => return,
+            };
+            data = rest;
+            self.pending.push(b);
+            match str::from_utf8(&self.pending) {
+                Ok(s) => {
+                    out.push_str(s);
+                    self.pending.clear();
+                }
+                Err(e) if e.error_len().is_some() => {
+                    self.error = Some(ParserErrorKind::InvalidUtf8);
+                    return;
+                }
+                Err(_) => (),
+            }
+        }
+
+        match str::from_utf8(data) {
+            Ok(s) => out.push_str(s),
+            Err(e) => {
+                let (valid, rest) = data.split_at(e.valid_up_to());
+                if let Ok(s) = str::from_utf8(valid) {
+                    out.push_str(s);
+                }
+                if e.error_len().is_some() {
+                    self.error = Some(ParserErrorKind::InvalidUtf8);
+                } else {
+                    // Incomplete sequence at the end of the slice
+                    self.pending.extend_from_slice(rest);
+                }
+            }
+        }
+    }
+
+    fn decode_utf16<F>(&mut self, data: &[u8], out: &mut String, unit: F)
+    where
+        F: Fn([u8; 2]) -> u16,
+    {
+        self.pending.extend_from_slice(data);
+
+        let buf = &self.pending;
+        out.reserve(buf.len() / 2);
+        let mut i = 0;
+        while i + 2 <= buf.len() {
+            let high = u32::from(unit([buf[i], buf[i + 1]]));
+            let c = match high {
+                0xD800..=0xDBFF => {
+                    // Wait for the low surrogate
+                    if i + 4 > buf.len() {
+                        break;
+                    }
+                    let low = u32::from(unit([buf[i + 2], buf[i + 3]]));
+                    if !(0xDC00..=0xDFFF).contains(&low) {
+                        self.error = Some(ParserErrorKind::InvalidUtf16);
+                        break;
+                    }
+                    i += 4;
+                    0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                }
+                0xDC00..=0xDFFF => {
+                    self.error = Some(ParserErrorKind::InvalidUtf16);
+                    break;
+                }
+                _ => {
+                    i += 2;
+                    high
+                }
+            };
+            out.push(char::from_u32(c).expect("Internal error: Invalid UTF-16 decoding"));
+        }
+
+        self.pending.drain(..i);
+    }
+}
+
+#[cfg(test)]
+mod encoding_tests {
+    use super::{Decoder, Encoding};
+    use crate::parser::ParserErrorKind;
+
+    fn decode_chunked(data: &[u8], chunk_size: usize) -> (String, Decoder) {
+        let mut decoder = Decoder::new();
+        let mut out = String::new();
+        for chunk in data.chunks(chunk_size) {
+            decoder.decode(chunk, &mut out);
+        }
+        (out, decoder)
+    }
+
+    #[test]
+    fn test_utf8_bom() {
+        let (out, decoder) = decode_chunked(b"\xEF\xBB\xBF<a>\xC3\xA4</a>", 1);
+        assert_eq!(out, "<a>\u{e4}</a>");
+        assert_eq!(decoder.encoding(), Some(Encoding::Utf8));
+    }
+
+    #[test]
+    fn test_utf16le_bom() {
+        let mut data = vec![0xFF, 0xFE];
+        for unit in "<a>\u{1f600}</a>".encode_utf16() {
+            data.extend_from_slice(&unit.to_le_bytes());
+        }
+        let (out, decoder) = decode_chunked(&data, 1);
+        assert_eq!(out, "<a>\u{1f600}</a>");
+        assert_eq!(decoder.encoding(), Some(Encoding::Utf16Le));
+    }
+
+    #[test]
+    fn test_utf16be_no_bom() {
+        let mut data = Vec::new();
+        for unit in "<?xml version='1.0'?><a/>".encode_utf16() {
+            data.extend_from_slice(&unit.to_be_bytes());
+        }
+        let (out, decoder) = decode_chunked(&data, 3);
+        assert_eq!(out, "<?xml version='1.0'?><a/>");
+        assert_eq!(decoder.encoding(), Some(Encoding::Utf16Be));
+    }
+
+    #[test]
+    fn test_latin1_declaration() {
+        let (out, decoder) = decode_chunked(
+            b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><a>\xE4</a>",
+            5,
+        );
+        assert_eq!(
+            out,
+            "<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><a>\u{e4}</a>"
+        );
+        assert_eq!(decoder.encoding(), Some(Encoding::Latin1));
+    }
+
+    #[test]
+    fn test_short_document() {
+        let (out, decoder) = decode_chunked(b"<a>", 3);
+        assert_eq!(out, "<a>");
+        assert_eq!(decoder.encoding(), Some(Encoding::Utf8));
+    }
+
+    #[test]
+    fn test_finish() {
+        let (mut out, mut decoder) = decode_chunked(b"<?x", 1);
+        assert_eq!(out, "");
+        decoder.finish(&mut out);
+        assert_eq!(out, "<?x");
+        assert_eq!(decoder.error(), None);
+
+        let (mut out, mut decoder) = decode_chunked(&[0xFF, 0xFE, b'<', 0, b'a'], 1);
+        decoder.finish(&mut out);
+        assert_eq!(out, "<");
+        assert_eq!(decoder.error(), Some(ParserErrorKind::InvalidUtf16));
+    }
+
+    #[test]
+    fn test_unsupported_encoding() {
+        let (_, decoder) = decode_chunked(b"<?xml version='1.0' encoding='EBCDIC'?><a/>", 4);
+        assert_eq!(decoder.error(), Some(ParserErrorKind::UnsupportedEncoding));
+    }
+}
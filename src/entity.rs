@@ -0,0 +1,391 @@
+// RustyXML
+// Copyright 2013-2016 RustyXML developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::parser::{is_char, is_name, ParserErrorKind};
+use crate::unescape_entity;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+// Default limit on the nesting of references to declared entities
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 16;
+// Default limit on the replacement text expanded per document, in bytes
+pub(crate) const DEFAULT_MAX_EXPANSION: usize = 10 * 1024 * 1024;
+
+fn trim_whitespace_start(input: &str) -> &str {
+    input.trim_start_matches(|c| c == ' ' || c == '\t' || c == '\r' || c == '\n')
+}
+
+// Returns the input following the first occurrence of `pattern`
+fn skip_past<'a>(input: &'a str, pattern: &str) -> Result<&'a str, ParserErrorKind> {
+    match input.find(pattern) {
+        Some(idx) => Ok(&input[idx + pattern.len()..]),
+        None => Err(ParserErrorKind::InvalidDoctype),
+    }
+}
+
+// Returns the input following the '>' closing a markup declaration
+fn skip_decl(input: &str) -> Result<&str, ParserErrorKind> {
+    let mut delim = None;
+    for (idx, c) in input.char_indices() {
+        match (delim, c) {
+            (None, '>') => return Ok(&input[idx + 1..]),
+            (None, '"') | (None, '\'') => delim = Some(c),
+            (Some(d), c) if c == d => delim = None,
+            _ => (),
+        }
+    }
+    Err(ParserErrorKind::InvalidDoctype)
+}
+
+// Appends text to `result`. In attribute values whitespace is replaced by spaces.
+fn push_text(result: &mut String, text: &str, attr: bool) {
+    if attr {
+        result.extend(text.chars().map(|c| match c {
+            '\t' | '\r' | '\n' => ' ',
+            c => c,
+        }));
+    } else {
+        result.push_str(text);
+    }
+}
+
+// Builds the replacement text of an entity from its literal value.
+// Character references are replaced, references to other entities are kept.
+fn replacement_text(value: &str, xml11: bool) -> Result<String, ParserErrorKind> {
+    if value.contains('%') {
+        // Parameter entity references are not allowed in the internal subset
+        return Err(ParserErrorKind::InvalidDoctype);
+    }
+
+    let mut result = String::with_capacity(value.len());
+    let mut it = value.split('&');
+    if let Some(sub) = it.next() {
+        result.push_str(sub);
+    }
+    for sub in it {
+        let idx = sub.find(';').ok_or(ParserErrorKind::InvalidEntity)?;
+        let ent = &sub[..idx];
+        if ent.starts_with('#') {
+            let c = unescape_entity(ent).ok_or(ParserErrorKind::InvalidEntity)?;
+            if !is_char(c, xml11) {
+                return Err(ParserErrorKind::InvalidChar);
+            }
+            result.push(c);
+        } else if !is_name(ent) {
+            return Err(ParserErrorKind::InvalidEntity);
+        } else {
+            result.push('&');
+            result.push_str(ent);
+            result.push(';');
+        }
+        result.push_str(&sub[idx + 1..]);
+    }
+    Ok(result)
+}
+
+/// Resolves references to named entities that are neither predefined nor declared in the DTD
+///
+/// A resolver is installed with `Parser::set_entity_resolver()`. The text it returns is
+/// inserted as character data, it is not parsed for further references or markup.
+pub trait EntityResolver {
+    /// Returns the replacement text for the entity `name`, or the error to report
+    fn resolve(&self, name: &str) -> Result<Cow<'_, str>, ParserErrorKind>;
+}
+
+// General entities declared in the internal DTD subset, and the resolver for other names.
+// Replacement text is treated as character data, markup within it is not parsed.
+pub(crate) struct Entities {
+    declared: HashMap<String, String>,
+    pub resolver: Option<Box<dyn EntityResolver + Send>>,
+    pub max_depth: usize,
+    pub max_expansion: usize,
+    // Whether character references follow XML 1.1 rules
+    pub xml11: bool,
+    // Bytes of replacement text expanded in the current document
+    expanded: usize,
+}
+
+impl Entities {
+    pub fn new() -> Entities {
+        Entities {
+            declared: HashMap::new(),
+            resolver: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_expansion: DEFAULT_MAX_EXPANSION,
+            xml11: false,
+            expanded: 0,
+        }
+    }
+
+    /// Forgets the entities declared in the current document, before parsing a new one
+    pub fn clear(&mut self) {
+        self.declared.clear();
+        self.xml11 = false;
+        self.expanded = 0;
+    }
+
+    /// Records the general entities declared in an internal DTD subset.
+    /// Parameter entities and external entities are skipped.
+    pub fn declare(&mut self, subset: &str) -> Result<(), ParserErrorKind> {
+        let mut rest = trim_whitespace_start(subset);
+        while !rest.is_empty() {
+            rest = if rest.starts_with("<!--") {
+                skip_past(&rest[4..], "-->")?
+            } else if rest.starts_with("<?") {
+                skip_past(&rest[2..], "?>")?
+            } else if rest.starts_with("<!ENTITY") {
+                self.entity_decl(&rest[8..])?
+            } else if rest.starts_with("<!") {
+                skip_decl(&rest[2..])?
+            } else if rest.starts_with('%') {
+                skip_past(rest, ";")?
+            } else {
+                return Err(ParserErrorKind::InvalidDoctype);
+            };
+            rest = trim_whitespace_start(rest);
+        }
+        Ok(())
+    }
+
+    // Parses an entity declaration following the ENTITY keyword, returning the remaining input
+    fn entity_decl<'a>(&mut self, input: &'a str) -> Result<&'a str, ParserErrorKind> {
+        let decl = trim_whitespace_start(input);
+        if decl.len() == input.len() {
+            return Err(ParserErrorKind::InvalidDoctype);
+        }
+        if decl.starts_with('%') {
+            return skip_decl(decl);
+        }
+
+        let name_len = decl
+            .find(|c| match c {
+                '"' | '\'' | '>' | ' ' | '\t' | '\r' | '\n' => true,
+                _ => false,
+            })
+            .unwrap_or(decl.len());
+        let (name, rest) = decl.split_at(name_len);
+        let def = trim_whitespace_start(rest);
+        if name.is_empty() || def.len() == rest.len() {
+            return Err(ParserErrorKind::InvalidDoctype);
+        }
+
+        let delim = match def.chars().next() {
+            Some(c @ '"') | Some(c @ '\'') => c,
+            // An external entity
+            _ => return skip_decl(def),
+        };
+        let end = match def[1..].find(delim) {
+            Some(end) => end + 1,
+            None => return Err(ParserErrorKind::InvalidDoctype),
+        };
+        if !is_name(name) {
+            return Err(ParserErrorKind::InvalidDoctype);
+        }
+        let value = replacement_text(&def[1..end], self.xml11)?;
+
+        let rest = trim_whitespace_start(&def[end + 1..]);
+        if !rest.starts_with('>') {
+            return Err(ParserErrorKind::InvalidDoctype);
+        }
+        // Only the first declaration of an entity is binding
+        self.declared.entry(name.to_owned()).or_insert(value);
+        Ok(&rest[1..])
+    }
+
+    /// Replaces character references as well as predefined and declared entities in `input`.
+    /// If `attr` is set, whitespace in the replacement text of entities is normalized to spaces,
+    /// as required in attribute values.
+    pub fn unescape<'a>(
+        &mut self,
+        input: &'a str,
+        attr: bool,
+    ) -> Result<Cow<'a, str>, ParserErrorKind> {
+        if !input.contains('&') {
+            return Ok(Cow::Borrowed(input));
+        }
+
+        let mut result = String::with_capacity(input.len());
+        let mut expanded = self.expanded;
+        let res = self.expand(input, 0, &mut expanded, &mut result, attr, false);
+        self.expanded = expanded;
+        res.map(|_| Cow::Owned(result))
+    }
+
+    /// Like `unescape()`, but keeps references that cannot be expanded as literal text
+    pub fn unescape_lenient(&mut self, input: &str, attr: bool) -> String {
+        let mut result = String::with_capacity(input.len());
+        let mut expanded = self.expanded;
+        // Expanding leniently never fails
+        let _ = self.expand(input, 0, &mut expanded, &mut result, attr, true);
+        self.expanded = expanded;
+        result
+    }
+
+    // Expands the references in `input`, which is replacement text if `depth` is not 0
+    fn expand(
+        &self,
+        input: &str,
+        depth: usize,
+        expanded: &mut usize,
+        result: &mut String,
+        attr: bool,
+        lenient: bool,
+    ) -> Result<(), ParserErrorKind> {
+        // The attribute value itself has been normalized already
+        let normalize = attr && depth > 0;
+        let mut it = input.split('&');
+        if let Some(sub) = it.next() {
+            push_text(result, sub, normalize);
+        }
+        for sub in it {
+            let len = result.len();
+            match self.expand_reference(sub, depth, expanded, result, attr, lenient) {
+                Ok(rest) => push_text(result, rest, normalize),
+                Err(_) if lenient => {
+                    result.truncate(len);
+                    result.push('&');
+                    push_text(result, sub, normalize);
+                }
+                Err(kind) => return Err(kind),
+            }
+        }
+        Ok(())
+    }
+
+    // Expands the reference at the start of `input`, following a '&'.
+    // Returns the input following the reference.
+    fn expand_reference<'a>(
+        &self,
+        input: &'a str,
+        depth: usize,
+        expanded: &mut usize,
+        result: &mut String,
+        attr: bool,
+        lenient: bool,
+    ) -> Result<&'a str, ParserErrorKind> {
+        let idx = input.find(';').ok_or(ParserErrorKind::InvalidEntity)?;
+        let ent = &input[..idx];
+        if let Some(c) = unescape_entity(ent) {
+            if ent.starts_with('#') && !is_char(c, self.xml11) {
+                return Err(ParserErrorKind::InvalidChar);
+            }
+            result.push(c);
+        } else if let Some(value) = self.declared.get(ent) {
+            if depth >= self.max_depth {
+                return Err(ParserErrorKind::EntityNestingTooDeep);
+            }
+            *expanded += value.len();
+            if *expanded > self.max_expansion {
+                return Err(ParserErrorKind::EntityExpansionTooLarge);
+            }
+            self.expand(value, depth + 1, expanded, result, attr, lenient)?;
+        } else if let Some(ref resolver) = self.resolver {
+            let value = resolver.resolve(ent)?;
+            *expanded += value.len();
+            if *expanded > self.max_expansion {
+                return Err(ParserErrorKind::EntityExpansionTooLarge);
+            }
+            push_text(result, &value, attr);
+        } else {
+            return Err(ParserErrorKind::InvalidEntity);
+        }
+        Ok(&input[idx + 1..])
+    }
+}
+
+#[cfg(test)]
+mod entity_tests {
+    use super::{Entities, EntityResolver};
+    use crate::parser::ParserErrorKind;
+    use std::borrow::Cow;
+
+    struct Upper;
+
+    impl EntityResolver for Upper {
+        fn resolve(&self, name: &str) -> Result<Cow<'_, str>, ParserErrorKind> {
+            match name {
+                "bad" => Err(ParserErrorKind::InvalidEntity),
+                name => Ok(Cow::Owned(name.to_uppercase())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_declare() {
+        let mut entities = Entities::new();
+        let subset = "<!-- <!ENTITY a 'comment'> --><?pi <!ENTITY a 'pi'>?>\n\
+                      <!ELEMENT a (#PCDATA)> <!ATTLIST a b CDATA '>'> %pe;\n\
+                      <!ENTITY % pe 'parameter'> <!ENTITY ext SYSTEM 'ext.xml'>\n\
+                      <!ENTITY a \"A&#38;#38;&b;\"> <!ENTITY a 'ignored'> <!ENTITY b 'B'>";
+        assert_eq!(entities.declare(subset), Ok(()));
+        assert_eq!(entities.unescape("&a;&lt;", false).unwrap(), "A&B<");
+        assert_eq!(
+            entities.unescape("&ext;", false),
+            Err(ParserErrorKind::InvalidEntity)
+        );
+    }
+
+    #[test]
+    fn test_declare_invalid() {
+        for subset in &["<!ENTITY a>", "<!ENTITY a 'b' c>", "<!ENTITY a '%b;'>", "x"] {
+            assert_eq!(
+                Entities::new().declare(subset),
+                Err(ParserErrorKind::InvalidDoctype)
+            );
+        }
+    }
+
+    #[test]
+    fn test_resolver() {
+        let mut entities = Entities::new();
+        entities.declare("<!ENTITY a 'declared &b;'>").unwrap();
+        entities.resolver = Some(Box::new(Upper));
+        assert_eq!(
+            entities.unescape("&a;, &c;&amp;", false).unwrap(),
+            "declared B, C&"
+        );
+        assert_eq!(
+            entities.unescape("&bad;", false),
+            Err(ParserErrorKind::InvalidEntity)
+        );
+    }
+
+    #[test]
+    fn test_unescape_lenient() {
+        let mut entities = Entities::new();
+        entities
+            .declare("<!ENTITY a '&a;'> <!ENTITY b 'B&c;'>")
+            .unwrap();
+        assert_eq!(
+            entities.unescape_lenient("AT&T &amp; &b; &#0; &a; &", false),
+            "AT&T & B&c; &#0; &a; &"
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut entities = Entities::new();
+        entities.declare("<!ENTITY a '&a;'>").unwrap();
+        assert_eq!(
+            entities.unescape("&a;", false),
+            Err(ParserErrorKind::EntityNestingTooDeep)
+        );
+
+        let mut entities = Entities::new();
+        entities.max_expansion = 1000;
+        entities
+            .declare("<!ENTITY a 'aaaaaaaaaa'> <!ENTITY b '&a;&a;&a;&a;&a;&a;&a;&a;&a;&a;'>")
+            .unwrap();
+        assert!(entities.unescape("&b;&b;&b;&b;&b;&b;&b;", false).is_ok());
+        assert_eq!(
+            entities.unescape("&b;", false),
+            Err(ParserErrorKind::EntityExpansionTooLarge)
+        );
+    }
+}
@@ -18,14 +18,30 @@
  * An XML parsing library
  */
 
+pub use crate::config::ParserConfig;
 pub use crate::element::ChildElements;
 pub use crate::element::Element;
 pub use crate::element_builder::BuilderError;
 pub use crate::element_builder::ElementBuilder;
+pub use crate::encoding::Encoding;
+pub use crate::entity::EntityResolver;
+#[cfg(feature = "html_entities")]
+pub use crate::html_entities::HtmlEntities;
+pub use crate::parser::BorrowedEvent;
+pub use crate::parser::BorrowedParser;
+pub use crate::parser::Construct;
 pub use crate::parser::Event;
 pub use crate::parser::Parser;
 pub use crate::parser::ParserError;
-
+pub use crate::parser::ParserErrorKind;
+pub use crate::parser::ParserState;
+pub use crate::parser::Position;
+pub use crate::parser::Pull;
+pub use crate::parser::Span;
+pub use crate::reader::EventReader;
+pub use crate::reader::ReaderError;
+
+use std::borrow::Cow;
 use std::char;
 use std::fmt;
 
@@ -34,9 +50,15 @@ pub use indexmap::IndexMap as AttrMap;
 #[cfg(not(feature = "ordered_attrs"))]
 pub use std::collections::HashMap as AttrMap;
 
+mod config;
 mod element;
 mod element_builder;
+mod encoding;
+mod entity;
+#[cfg(feature = "html_entities")]
+mod html_entities;
 mod parser;
+mod reader;
 
 // General functions
 
@@ -75,25 +97,9 @@ pub fn unescape(input: &str) -> Result<String, String> {
         match sub.find(';') {
             Some(idx) => {
                 let ent = &sub[..idx];
-                match ent {
-                    "quot" => result.push('"'),
-                    "apos" => result.push('\''),
-                    "gt" => result.push('>'),
-                    "lt" => result.push('<'),
-                    "amp" => result.push('&'),
-                    ent => {
-                        let val = if ent.starts_with("#x") {
-                            u32::from_str_radix(&ent[2..], 16).ok()
-                        } else if ent.starts_with('#') {
-                            u32::from_str_radix(&ent[1..], 10).ok()
-                        } else {
-                            None
-                        };
-                        match val.and_then(char::from_u32) {
-                            Some(c) => result.push(c),
-                            None => return Err(format!("&{};", ent)),
-                        }
-                    }
+                match unescape_entity(ent) {
+                    Some(c) => result.push(c),
+                    None => return Err(format!("&{};", ent)),
                 }
                 result.push_str(&sub[idx + 1..]);
             }
@@ -103,6 +109,27 @@ pub fn unescape(input: &str) -> Result<String, String> {
     Ok(result)
 }
 
+// Returns the character a predefined entity or character reference refers to
+fn unescape_entity(ent: &str) -> Option<char> {
+    match ent {
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "gt" => Some('>'),
+        "lt" => Some('<'),
+        "amp" => Some('&'),
+        ent => {
+            let val = if ent.starts_with("#x") {
+                u32::from_str_radix(&ent[2..], 16).ok()
+            } else if ent.starts_with('#') {
+                ent[1..].parse().ok()
+            } else {
+                None
+            };
+            val.and_then(char::from_u32)
+        }
+    }
+}
+
 // General types
 #[derive(Clone, PartialEq, Debug)]
 /// An Enum describing a XML Node
@@ -116,7 +143,7 @@ pub enum Xml {
     /// A XML Comment
     CommentNode(String),
     /// Processing Information
-    PINode(String),
+    PINode(ProcessingInstruction),
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -130,6 +157,10 @@ pub struct StartTag {
     pub prefix: Option<String>,
     /// The tag's attributes
     pub attributes: AttrMap<(String, Option<String>), String>,
+    /// The namespace bindings declared on this tag as (prefix, namespace) pairs, in document order
+    ///
+    /// A prefix of `None` declares the default namespace, an empty namespace undeclares it.
+    pub namespaces: Vec<(Option<String>, String)>,
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -143,6 +174,179 @@ pub struct EndTag {
     pub prefix: Option<String>,
 }
 
+#[derive(PartialEq, Eq, Debug)]
+/// Structure describing an opening tag, borrowing from the parser's input where possible
+pub struct BorrowedStartTag<'a> {
+    /// The tag's name
+    pub name: Cow<'a, str>,
+    /// The tag's namespace
+    pub ns: Option<Cow<'a, str>>,
+    /// The tag's prefix
+    pub prefix: Option<Cow<'a, str>>,
+    /// The tag's attributes
+    #[allow(clippy::type_complexity)]
+    pub attributes: AttrMap<(Cow<'a, str>, Option<Cow<'a, str>>), Cow<'a, str>>,
+    /// The namespace bindings declared on this tag as (prefix, namespace) pairs, in document order
+    pub namespaces: Vec<(Option<Cow<'a, str>>, Cow<'a, str>)>,
+}
+
+impl<'a> BorrowedStartTag<'a> {
+    /// Converts this into a `StartTag`, copying all borrowed data
+    pub fn into_owned(self) -> StartTag {
+        StartTag {
+            name: self.name.into_owned(),
+            ns: self.ns.map(Cow::into_owned),
+            prefix: self.prefix.map(Cow::into_owned),
+            attributes: self
+                .attributes
+                .into_iter()
+                .map(|((name, ns), value)| {
+                    (
+                        (name.into_owned(), ns.map(Cow::into_owned)),
+                        value.into_owned(),
+                    )
+                })
+                .collect(),
+            namespaces: self
+                .namespaces
+                .into_iter()
+                .map(|(prefix, ns)| (prefix.map(Cow::into_owned), ns.into_owned()))
+                .collect(),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+/// Structure describing a closing tag, borrowing from the parser's input where possible
+pub struct BorrowedEndTag<'a> {
+    /// The tag's name
+    pub name: Cow<'a, str>,
+    /// The tag's namespace
+    pub ns: Option<Cow<'a, str>>,
+    /// The tag's prefix
+    pub prefix: Option<Cow<'a, str>>,
+}
+
+impl<'a> BorrowedEndTag<'a> {
+    /// Converts this into an `EndTag`, copying all borrowed data
+    pub fn into_owned(self) -> EndTag {
+        EndTag {
+            name: self.name.into_owned(),
+            ns: self.ns.map(Cow::into_owned),
+            prefix: self.prefix.map(Cow::into_owned),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Structure describing a processing instruction
+pub struct ProcessingInstruction {
+    /// The name of the application the instruction is directed to
+    pub target: String,
+    /// The instruction's content, empty if there is none
+    pub data: String,
+}
+
+impl fmt::Display for ProcessingInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.data.is_empty() {
+            write!(f, "<?{}?>", self.target)
+        } else {
+            write!(f, "<?{} {}?>", self.target, self.data)
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+/// Structure describing a processing instruction, borrowing from the parser's input
+pub struct BorrowedProcessingInstruction<'a> {
+    /// The name of the application the instruction is directed to
+    pub target: Cow<'a, str>,
+    /// The instruction's content, empty if there is none
+    pub data: Cow<'a, str>,
+}
+
+impl<'a> BorrowedProcessingInstruction<'a> {
+    /// Converts this into a `ProcessingInstruction`, copying all borrowed data
+    pub fn into_owned(self) -> ProcessingInstruction {
+        ProcessingInstruction {
+            target: self.target.into_owned(),
+            data: self.data.into_owned(),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Structure describing an XML declaration
+pub struct XmlDecl {
+    /// The XML version the document conforms to
+    pub version: String,
+    /// The encoding the document declares
+    pub encoding: Option<String>,
+    /// Whether the document declares itself standalone
+    pub standalone: Option<bool>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+/// Structure describing an XML declaration, borrowing from the parser's input
+pub struct BorrowedXmlDecl<'a> {
+    /// The XML version the document conforms to
+    pub version: Cow<'a, str>,
+    /// The encoding the document declares
+    pub encoding: Option<Cow<'a, str>>,
+    /// Whether the document declares itself standalone
+    pub standalone: Option<bool>,
+}
+
+impl<'a> BorrowedXmlDecl<'a> {
+    /// Converts this into an `XmlDecl`, copying all borrowed data
+    pub fn into_owned(self) -> XmlDecl {
+        XmlDecl {
+            version: self.version.into_owned(),
+            encoding: self.encoding.map(Cow::into_owned),
+            standalone: self.standalone,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+/// Structure describing a document type declaration
+pub struct Doctype {
+    /// The name of the document's root element
+    pub name: String,
+    /// The public identifier of the external DTD subset
+    pub public_id: Option<String>,
+    /// The system identifier of the external DTD subset
+    pub system_id: Option<String>,
+    /// The raw text of the internal DTD subset
+    pub internal_subset: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+/// Structure describing a document type declaration, borrowing from the parser's input
+pub struct BorrowedDoctype<'a> {
+    /// The name of the document's root element
+    pub name: Cow<'a, str>,
+    /// The public identifier of the external DTD subset
+    pub public_id: Option<Cow<'a, str>>,
+    /// The system identifier of the external DTD subset
+    pub system_id: Option<Cow<'a, str>>,
+    /// The raw text of the internal DTD subset
+    pub internal_subset: Option<Cow<'a, str>>,
+}
+
+impl<'a> BorrowedDoctype<'a> {
+    /// Converts this into a `Doctype`, copying all borrowed data
+    pub fn into_owned(self) -> Doctype {
+        Doctype {
+            name: self.name.into_owned(),
+            public_id: self.public_id.map(Cow::into_owned),
+            system_id: self.system_id.map(Cow::into_owned),
+            internal_subset: self.internal_subset.map(Cow::into_owned),
+        }
+    }
+}
+
 impl fmt::Display for Xml {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -150,14 +354,14 @@ impl fmt::Display for Xml {
             Xml::CharacterNode(ref data) => write!(f, "{}", escape(data)),
             Xml::CDATANode(ref data) => write!(f, "<![CDATA[{}]]>", data),
             Xml::CommentNode(ref data) => write!(f, "<!--{}-->", data),
-            Xml::PINode(ref data) => write!(f, "<?{}?>", data),
+            Xml::PINode(ref pi) => pi.fmt(f),
         }
     }
 }
 
 #[cfg(test)]
 mod lib_tests {
-    use super::{escape, unescape, Element, Xml};
+    use super::{escape, unescape, Element, ProcessingInstruction, Xml};
 
     #[test]
     fn test_escape() {
@@ -252,14 +456,22 @@ mod lib_tests {
 
     #[test]
     fn test_show_pi() {
-        let chars = Xml::PINode("xml version='1.0'".to_owned());
-        assert_eq!(format!("{}", chars), "<?xml version='1.0'?>");
+        let pi = Xml::PINode(ProcessingInstruction {
+            target: "xml-stylesheet".to_owned(),
+            data: "href='style.css'".to_owned(),
+        });
+        assert_eq!(format!("{}", pi), "<?xml-stylesheet href='style.css'?>");
+        let pi = Xml::PINode(ProcessingInstruction {
+            target: "break".to_owned(),
+            data: String::new(),
+        });
+        assert_eq!(format!("{}", pi), "<?break?>");
     }
 
     #[test]
     fn test_content_str() {
         let mut elem = Element::new("a".to_owned(), None, vec![]);
-        elem.pi("processing information".to_owned())
+        elem.pi("processing".to_owned(), "information".to_owned())
             .cdata("<hello/>".to_owned())
             .tag_stay(Element::new("b".to_owned(), None, vec![]))
             .text("World".to_owned())
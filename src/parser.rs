@@ -11,18 +11,27 @@
 // ObjFW, Copyright (c) 2008-2013 Jonathan Schleifer.
 // Permission to license this derived work under MIT license has been granted by ObjFW's author.
 
-use crate::{unescape, AttrMap, EndTag, StartTag};
-use std::collections::{HashMap, VecDeque};
+use crate::config::ParserConfig;
+use crate::encoding::{Decoder, Encoding};
+use crate::entity::{Entities, EntityResolver};
+use crate::{
+    AttrMap, BorrowedDoctype, BorrowedEndTag, BorrowedProcessingInstruction, BorrowedStartTag,
+    BorrowedXmlDecl, Doctype, EndTag, ProcessingInstruction, StartTag, XmlDecl,
+};
+use std::borrow::Cow;
 use std::error::Error;
 use std::fmt;
 use std::iter::Iterator;
 use std::mem;
+use std::ops::Range;
 
 #[derive(PartialEq, Eq, Debug)]
 /// Events returned by the `Parser`
 pub enum Event {
+    /// Event indicating the XML declaration was found
+    XmlDecl(XmlDecl),
     /// Event indicating processing information was found
-    PI(String),
+    PI(ProcessingInstruction),
     /// Event indicating a start tag was found
     ElementStart(StartTag),
     /// Event indicating a end tag was found
@@ -33,6 +42,50 @@ pub enum Event {
     CDATA(String),
     /// Event indicating a comment was found
     Comment(String),
+    /// Event indicating a document type declaration was found
+    Doctype(Doctype),
+    /// Event indicating the end of a document, see `ParserConfig::concatenated_documents()`
+    DocumentEnd,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+/// Events returned by the `BorrowedParser`, borrowing from its input where possible
+pub enum BorrowedEvent<'a> {
+    /// Event indicating the XML declaration was found
+    XmlDecl(BorrowedXmlDecl<'a>),
+    /// Event indicating processing information was found
+    PI(BorrowedProcessingInstruction<'a>),
+    /// Event indicating a start tag was found
+    ElementStart(BorrowedStartTag<'a>),
+    /// Event indicating a end tag was found
+    ElementEnd(BorrowedEndTag<'a>),
+    /// Event indicating character data was found
+    Characters(Cow<'a, str>),
+    /// Event indicating CDATA was found
+    CDATA(Cow<'a, str>),
+    /// Event indicating a comment was found
+    Comment(Cow<'a, str>),
+    /// Event indicating a document type declaration was found
+    Doctype(BorrowedDoctype<'a>),
+    /// Event indicating the end of a document, see `ParserConfig::concatenated_documents()`
+    DocumentEnd,
+}
+
+impl<'a> BorrowedEvent<'a> {
+    /// Converts this into an `Event`, copying all borrowed data
+    pub fn into_owned(self) -> Event {
+        match self {
+            BorrowedEvent::XmlDecl(decl) => Event::XmlDecl(decl.into_owned()),
+            BorrowedEvent::PI(pi) => Event::PI(pi.into_owned()),
+            BorrowedEvent::ElementStart(tag) => Event::ElementStart(tag.into_owned()),
+            BorrowedEvent::ElementEnd(tag) => Event::ElementEnd(tag.into_owned()),
+            BorrowedEvent::Characters(data) => Event::Characters(data.into_owned()),
+            BorrowedEvent::CDATA(data) => Event::CDATA(data.into_owned()),
+            BorrowedEvent::Comment(data) => Event::Comment(data.into_owned()),
+            BorrowedEvent::Doctype(doctype) => Event::Doctype(doctype.into_owned()),
+            BorrowedEvent::DocumentEnd => Event::DocumentEnd,
+        }
+    }
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -41,8 +94,12 @@ pub enum Event {
 pub struct ParserError {
     /// The line number at which the error occurred
     pub line: u32,
-    /// The column number at which the error occurred
+    /// The column number at which the error occurred, starting at 1
+    ///
+    /// Together with `line` it describes the position of `offset`.
     pub col: u32,
+    /// The byte offset in the input up to which it had been parsed when the error occurred
+    pub offset: usize,
     /// The kind of error encountered
     pub kind: ParserErrorKind,
 }
@@ -59,22 +116,99 @@ impl fmt::Display for ParserError {
     }
 }
 
-#[derive(PartialEq, Debug, Copy, Clone)]
+#[derive(PartialEq, Debug, Clone)]
 #[non_exhaustive]
+/// The kinds of errors the `Parser` can encounter
 pub enum ParserErrorKind {
+    /// A tag name used a prefix that is not bound to a namespace
     UnboundNsPrefixInTagName,
+    /// An attribute name used a prefix that is not bound to a namespace
     UnboundNsPrefixInAttributeName,
+    /// An attribute name contained whitespace
     SpaceInAttributeName,
+    /// An attribute was specified more than once on the same element
     DuplicateAttribute,
+    /// An attribute value was not enclosed in quotes
     UndelimitedAttribute,
+    /// An unknown or malformed entity reference was found
     InvalidEntity,
+    /// The opening sequence of a CDATA section was malformed
     InvalidCdataStart,
+    /// The opening sequence of a comment was malformed
     InvalidCommentStart,
+    /// A comment contained "--"
     InvalidCommentContent,
+    /// A DOCTYPE declaration was malformed
     InvalidDoctype,
+    /// Expected '>' to close a tag
     ExpectedTagClose,
+    /// Expected '>' or whitespace in a closing tag
     ExpectedLwsOrTagClose,
+    /// Generic error for otherwise malformed XML
     MalformedXml,
+    /// Data fed as bytes was not valid UTF-8
+    InvalidUtf8,
+    /// Data fed as bytes was not valid UTF-16
+    InvalidUtf16,
+    /// The document declared an encoding that is not supported
+    UnsupportedEncoding,
+    /// References to declared entities were nested deeper than allowed
+    EntityNestingTooDeep,
+    /// Expanding declared entities produced more text than allowed
+    EntityExpansionTooLarge,
+    /// The XML declaration was malformed
+    InvalidXmlDecl,
+    /// An XML declaration was found after the start of the document
+    MisplacedXmlDecl,
+    /// A processing instruction had a missing or invalid target
+    InvalidPITarget,
+    /// A processing instruction used a target reserved by the XML specification
+    ReservedPITarget,
+    /// An end tag did not match the innermost open element (strict mode only)
+    MismatchedEndTag {
+        /// The name of the innermost open element
+        expected: String,
+        /// The name found in the end tag
+        found: String,
+    },
+    /// An end tag was found while no element was open (strict mode only)
+    UnexpectedEndTag {
+        /// The name found in the end tag
+        found: String,
+    },
+    /// A second root element was found (strict mode only)
+    MultipleRootElements {
+        /// The name of the second root element
+        found: String,
+    },
+    /// Character data other than whitespace was found outside the root element
+    /// (strict mode only)
+    TextOutsideRoot,
+    /// An element, attribute or other name contained characters not allowed in names
+    InvalidName,
+    /// A character not allowed in XML documents was found, directly or as a reference
+    InvalidChar,
+    /// More elements were open at once than allowed, see `ParserConfig::max_depth()`
+    NestingTooDeep,
+    /// An element had more attributes than allowed, see `ParserConfig::max_attributes()`
+    TooManyAttributes,
+    /// A name was longer than allowed, see `ParserConfig::max_name_length()`
+    NameTooLong,
+    /// An attribute value was larger than allowed,
+    /// see `ParserConfig::max_attribute_value_size()`
+    AttributeValueTooLarge,
+    /// Character data was larger than allowed, see `ParserConfig::max_text_size()`
+    TextTooLarge,
+    /// A construct required buffering more input than allowed,
+    /// see `ParserConfig::max_buffer_size()`
+    InputBufferTooLarge,
+    /// The input ended before the document was complete, see `Parser::finish()`
+    UnexpectedEof {
+        /// The construct left unterminated, if any
+        construct: Option<Construct>,
+        /// The names of the elements left open, outermost first
+        open_elements: Vec<String>,
+    },
 }
 
 impl fmt::Display for ParserErrorKind {
@@ -97,6 +231,134 @@ impl fmt::Display for ParserErrorKind {
             ParserErrorKind::ExpectedTagClose => "Expected '>' to close tag",
             ParserErrorKind::ExpectedLwsOrTagClose => "Expected '>' to close tag, or LWS",
             ParserErrorKind::MalformedXml => "Malformed XML",
+            ParserErrorKind::InvalidUtf8 => "Invalid UTF-8",
+            ParserErrorKind::InvalidUtf16 => "Invalid UTF-16",
+            ParserErrorKind::UnsupportedEncoding => "Unsupported encoding",
+            ParserErrorKind::EntityNestingTooDeep => "Entity references nested too deeply",
+            ParserErrorKind::EntityExpansionTooLarge => "Entity expansion too large",
+            ParserErrorKind::InvalidXmlDecl => "Malformed XML declaration",
+            ParserErrorKind::MisplacedXmlDecl => "XML declaration not at start of document",
+            ParserErrorKind::InvalidPITarget => "Invalid processing instruction target",
+            ParserErrorKind::ReservedPITarget => "Reserved processing instruction target",
+            ParserErrorKind::MismatchedEndTag {
+                ref expected,
+                ref found,
+            } => {
+                return write!(
+                    f,
+                    "Mismatched end tag, expected '{}', found '{}'",
+                    expected, found
+                )
+            }
+            ParserErrorKind::UnexpectedEndTag { ref found } => {
+                return write!(f, "Unexpected end tag '{}'", found)
+            }
+            ParserErrorKind::MultipleRootElements { ref found } => {
+                return write!(f, "Second root element '{}'", found)
+            }
+            ParserErrorKind::TextOutsideRoot => "Character data outside of root element",
+            ParserErrorKind::InvalidName => "Invalid name",
+            ParserErrorKind::InvalidChar => "Character not allowed in XML",
+            ParserErrorKind::NestingTooDeep => "Elements nested too deeply",
+            ParserErrorKind::TooManyAttributes => "Too many attributes",
+            ParserErrorKind::NameTooLong => "Name too long",
+            ParserErrorKind::AttributeValueTooLarge => "Attribute value too large",
+            ParserErrorKind::TextTooLarge => "Character data too large",
+            ParserErrorKind::InputBufferTooLarge => "Construct too large to buffer",
+            ParserErrorKind::UnexpectedEof {
+                ref construct,
+                ref open_elements,
+            } => {
+                f.write_str("Unexpected end of input")?;
+                if let Some(ref construct) = *construct {
+                    write!(f, " in {}", construct)?;
+                }
+                if !open_elements.is_empty() {
+                    write!(f, ", unclosed elements: {}", open_elements.join(", "))?;
+                }
+                return Ok(());
+            }
+        };
+        msg.fmt(f)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+/// A position in the input of a `Parser`
+///
+/// Offsets count bytes of the input as UTF-8, as fed to `Parser::feed_str()`
+/// or decoded by `Parser::feed_bytes()`, excluding any byte order mark.
+pub struct Position {
+    /// The byte offset from the start of the input
+    pub offset: usize,
+    /// The line number, starting at 1
+    pub line: u32,
+    /// The column number of the character at `offset`, counted in characters and starting at 1
+    pub col: u32,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+/// The part of the input an event was parsed from, as returned by `Parser::span()`
+pub struct Span {
+    /// The position of the first character
+    pub start: Position,
+    /// The position following the last character
+    pub end: Position,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+/// The result of pulling from a `Parser` with `Parser::pull()`
+pub enum Pull {
+    /// An event was parsed
+    Event(Event),
+    /// All data fed so far has been parsed, more is needed to continue
+    NeedMoreInput,
+    /// All data fed so far has been parsed, and the document is complete
+    EndOfDocument,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[non_exhaustive]
+/// Where in a document the `Parser` currently is, as returned by `Parser::state()`
+pub enum ParserState {
+    /// Outside of the root element and any other construct,
+    /// either before the root element or after it has been closed
+    BetweenDocuments,
+    /// Inside the root element, outside of any markup
+    InText,
+    /// In the middle of the given construct
+    InMarkup(Construct),
+    /// An error has been encountered, no more events will be produced
+    Failed,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[non_exhaustive]
+/// The kinds of constructs the parser can be in the middle of
+pub enum Construct {
+    /// A start or end tag
+    Tag,
+    /// An attribute value
+    AttributeValue,
+    /// A comment
+    Comment,
+    /// A CDATA section
+    CDATA,
+    /// A processing instruction or the XML declaration
+    ProcessingInstruction,
+    /// A document type declaration
+    Doctype,
+}
+
+impl fmt::Display for Construct {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match *self {
+            Construct::Tag => "tag",
+            Construct::AttributeValue => "attribute value",
+            Construct::Comment => "comment",
+            Construct::CDATA => "CDATA section",
+            Construct::ProcessingInstruction => "processing instruction",
+            Construct::Doctype => "DOCTYPE declaration",
         };
         msg.fmt(f)
     }
@@ -122,13 +384,25 @@ enum State {
     InComment1,
     InComment2,
     InDoctype,
+    InDoctypeDecl,
+    InDoctypeLiteral,
+    InDoctypeSubset,
+    InDoctypeSubsetLiteral,
+    InDoctypeSubsetMarkup,
+    InDoctypeSubsetComment,
+    InDoctypeSubsetPI,
+    InDoctypeEnd,
+    SkipMarkup,
 }
 
 /// A streaming XML parser
 ///
-/// Data is fed to the parser using the `feed_str()` method.
+/// Data is fed to the parser using the `feed_str()` or `feed_bytes()` methods.
 /// The `Event`s, and `ParserError`s generated while parsing the string
-/// can be requested by iterating over the parser
+/// can be requested by iterating over the parser.
+/// Once all data has been fed, `finish()` reports whether the document was complete.
+/// To parse data from an `io::Read` use an `EventReader`.
+/// Options are set using a `ParserConfig` passed to `with_config()`, or the setters below.
 ///
 /// ~~~
 /// use xml::Parser;
@@ -145,39 +419,124 @@ enum State {
 pub struct Parser {
     line: u32,
     col: u32,
+    // Whether the last character parsed was a carriage return
+    last_cr: bool,
     has_error: bool,
-    data: VecDeque<char>,
-    buf: String,
-    namespaces: Vec<HashMap<String, String>>,
-    attributes: Vec<(String, Option<String>, String)>,
+    // Input that has not been discarded yet.
+    // All offsets below are absolute, `base` is the offset of the first byte in `data`.
+    data: String,
+    base: usize,
+    // Offset of the next byte to parse
+    pos: usize,
+    // Offset up to which the input has been checked for characters not allowed in XML
+    end: usize,
+    // Whether such a character was found at `end`
+    char_error: bool,
+    // Whether the document declared XML version 1.1
+    xml11: bool,
+    // Offset at which the construct currently being parsed starts.
+    // Input before this is no longer needed.
+    mark: usize,
+    // Position of `mark`
+    mark_position: Position,
+    // Span of the last event or error produced
+    span: Span,
+    // Offset at which the name or content currently being parsed starts
+    start: usize,
+    decoder: Decoder,
+    entities: Entities,
+    // Namespace bindings as (prefix, namespace) pairs, innermost last,
+    // along with the range of the namespace in the input if it can be borrowed from there
+    namespaces: Vec<(String, String, Option<Range<usize>>)>,
+    // Number of bindings in scope outside of each open element
+    ns_scopes: Vec<usize>,
+    // Whether the scope of the element closed by the last event is still to be removed
+    closed_scope: bool,
+    // Qualified names of the open elements, concatenated
+    open_names: String,
+    // End offset in `open_names` of each open element's name
+    open_ends: Vec<usize>,
+    // Whether the root element has been opened
+    seen_root: bool,
+    // Offset at which the current document starts
+    doc_start: usize,
+    // Whether the current document follows another one, in concatenated documents mode
+    follows_document: bool,
+    // Whether DocumentEnd is to be produced before parsing continues
+    pending_document_end: bool,
+    // Whether the end of input has been signalled
+    finished: bool,
+    // Whether input was rejected as it exceeded the limit on buffered input
+    buffer_exceeded: bool,
+    config: ParserConfig,
+    // Errors recovered from in lenient mode
+    diagnostics: Vec<ParserError>,
+    // Text to be coalesced into a single event, and its span if there is any
+    text: String,
+    text_span: Option<Span>,
+    // Number of open elements to close before parsing continues, in lenient mode
+    pending_closes: usize,
+    // Attributes as name, raw value and, if it contained entities, unescaped value
+    attributes: Vec<(Range<usize>, Range<usize>, Option<String>)>,
     st: State,
-    name: Option<(Option<String>, String)>,
-    attr: Option<(Option<String>, String)>,
+    name: Option<Range<usize>>,
+    attr: Option<Range<usize>>,
     delim: Option<char>,
     level: u8,
 }
 
 impl Parser {
     /// Returns a new `Parser`
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Parser {
-        let mut ns = HashMap::with_capacity(2);
-        // Add standard namespaces
-        ns.insert(
-            "xml".to_owned(),
-            "http://www.w3.org/XML/1998/namespace".to_owned(),
-        );
-        ns.insert(
-            "xmlns".to_owned(),
-            "http://www.w3.org/2000/xmlns/".to_owned(),
-        );
+        Parser::with_config(ParserConfig::new())
+    }
+
+    /// Returns a new `Parser` using the given configuration
+    pub fn with_config(config: ParserConfig) -> Parser {
+        let mut entities = Entities::new();
+        entities.max_depth = config.max_entity_depth;
+        entities.max_expansion = config.max_entity_expansion;
+
+        let ns = initial_namespaces(&config);
 
         Parser {
             line: 1,
             col: 0,
+            last_cr: false,
             has_error: false,
-            data: VecDeque::with_capacity(4096),
-            buf: String::new(),
-            namespaces: vec![ns],
+            data: String::with_capacity(4096),
+            base: 0,
+            pos: 0,
+            end: 0,
+            char_error: false,
+            xml11: false,
+            mark: 0,
+            mark_position: Position {
+                offset: 0,
+                line: 1,
+                col: 1,
+            },
+            span: Span::default(),
+            start: 0,
+            decoder: Decoder::new(),
+            entities,
+            namespaces: ns,
+            ns_scopes: Vec::new(),
+            closed_scope: false,
+            open_names: String::new(),
+            open_ends: Vec::new(),
+            seen_root: false,
+            doc_start: 0,
+            follows_document: false,
+            pending_document_end: false,
+            finished: false,
+            buffer_exceeded: false,
+            config,
+            diagnostics: Vec::new(),
+            text: String::new(),
+            text_span: None,
+            pending_closes: 0,
             attributes: Vec::new(),
             st: State::OutsideTag,
             name: None,
@@ -187,761 +546,3905 @@ impl Parser {
         }
     }
 
+    /// Resets the parser to parse a new document, e.g. when an XMPP stream is restarted
+    ///
+    /// Open elements, namespace bindings and declared entities are forgotten,
+    /// and errors no longer end parsing. The configuration and entity resolver are kept,
+    /// as are the positions of events and errors, which continue from where parsing stopped.
+    /// If `keep_input` is true, input fed but not parsed yet is parsed as the start
+    /// of the new document, otherwise it is discarded. `finish()` has to be called again
+    /// at the end of the new document.
+    ///
+    /// ~~~
+    /// use xml::{Event, Parser};
+    ///
+    /// let mut p = Parser::new();
+    /// p.feed_str("<stream><proceed/><stream><message/>");
+    /// p.nth(2);
+    /// p.reset(true);
+    /// assert!(match p.next() {
+    ///     Some(Ok(Event::ElementStart(tag))) => tag.name == "stream",
+    ///     _ => false,
+    /// });
+    /// ~~~
+    pub fn reset(&mut self, keep_input: bool) {
+        if !keep_input {
+            self.data.truncate(self.pos - self.base);
+            self.decoder = Decoder::new();
+        }
+        self.has_error = false;
+        self.finished = false;
+        self.buffer_exceeded = false;
+        self.set_mark();
+        self.start = self.pos;
+        self.text.clear();
+        self.text_span = None;
+        self.namespaces = initial_namespaces(&self.config);
+        self.ns_scopes.clear();
+        self.closed_scope = false;
+        self.open_names.clear();
+        self.open_ends.clear();
+        self.pending_closes = 0;
+        self.pending_document_end = false;
+        self.follows_document = false;
+        self.attributes.clear();
+        self.st = State::OutsideTag;
+        self.name = None;
+        self.attr = None;
+        self.delim = None;
+        self.level = 0;
+        self.start_document();
+    }
+
     /// Feeds a string slice to the parser
+    ///
+    /// A byte order mark at the start of the document is skipped.
+    /// Data exceeding `ParserConfig::max_buffer_size()` is rejected,
+    /// which is reported as an error once the data buffered before has been parsed.
     pub fn feed_str(&mut self, data: &str) {
-        self.data.extend(data.chars());
+        self.compact();
+        if self.buffer_exceeded || self.buffered() + data.len() > self.config.max_buffer_size {
+            self.buffer_exceeded = true;
+            return;
+        }
+        self.data.push_str(data);
     }
-}
 
-impl Iterator for Parser {
-    type Item = Result<Event, ParserError>;
+    /// Feeds a slice of bytes to the parser
+    ///
+    /// The encoding is detected from a byte order mark or the XML declaration,
+    /// as described in appendix F of the XML specification. Supported are UTF-8,
+    /// UTF-16 and ISO-8859-1, with UTF-8 as the default.
+    ///
+    /// The data does not have to end on a character boundary, incomplete sequences
+    /// are completed by subsequent calls. Invalid or unsupported input is reported as
+    /// an error once all data preceding it has been parsed.
+    /// Data exceeding `ParserConfig::max_buffer_size()` once decoded is rejected the same way.
+    pub fn feed_bytes(&mut self, data: &[u8]) {
+        self.compact();
+        if self.buffer_exceeded {
+            return;
+        }
+        let len = self.data.len();
+        self.decoder.decode(data, &mut self.data);
+        if self.buffered() > self.config.max_buffer_size {
+            self.data.truncate(len);
+            self.buffer_exceeded = true;
+        }
+    }
 
-    fn next(&mut self) -> Option<Result<Event, ParserError>> {
+    /// Enables or disables strict well-formedness checks
+    ///
+    /// In strict mode end tags have to match the innermost open element,
+    /// only a single root element is allowed, and no character data other than whitespace
+    /// may appear outside of it. Violations are reported as `MismatchedEndTag`,
+    /// `UnexpectedEndTag`, `MultipleRootElements` and `TextOutsideRoot` errors respectively.
+    /// Otherwise only the `ElementBuilder` detects such errors. Strict mode is disabled by default.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.config.strict = strict;
+    }
+
+    /// Enables or disables lenient parsing
+    ///
+    /// In lenient mode errors are not returned while iterating over the parser. They are
+    /// recorded, see `diagnostics()`, and parsing continues as follows:
+    ///
+    /// * Entity and character references that cannot be expanded are kept as text,
+    ///   so a stray `&` is treated as text.
+    /// * Invalid names and characters are accepted as written.
+    /// * Elements with an unbound prefix are reported without a namespace,
+    ///   attributes with an unbound prefix are dropped.
+    ///   Of duplicate attributes the first one is kept.
+    /// * Any other malformed markup is skipped up to and including the next `>`.
+    ///   An empty-element tag that is not closed properly still produces its `ElementEnd`.
+    /// * Elements left open at the end of input are closed, producing `ElementEnd` events.
+    /// * An end tag not matching the innermost open element closes all elements
+    ///   up to the matching one, or is skipped if there is none.
+    ///   This is recorded as `MismatchedEndTag` or `UnexpectedEndTag` even if strict mode
+    ///   is disabled, so that elements are always properly nested.
+    /// * In strict mode additional root elements and character data outside the root element
+    ///   are accepted.
+    ///
+    /// Input that cannot be decoded, and input exceeding a limit set in `ParserConfig`,
+    /// are still returned as errors, and end parsing.
+    /// Lenient mode is disabled by default.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.config.lenient = lenient;
+    }
+
+    /// Returns the errors recovered from in lenient mode so far
+    pub fn diagnostics(&self) -> &[ParserError] {
+        &self.diagnostics
+    }
+
+    /// Enables or disables the normalization of line breaks and attribute values
+    ///
+    /// As required by the XML specification, line breaks in character data, CDATA sections,
+    /// comments, processing instructions and the internal DTD subset are reported as `\n`,
+    /// whether written as `\r\n`, `\r` or `\n`. Documents declaring version 1.1 also have
+    /// `\u{85}`, `\r\u{85}` and `\u{2028}` normalized. In attribute values each line break and
+    /// tab is additionally replaced by a space, also within the replacement text of entities.
+    /// Characters written as character references are kept as is.
+    ///
+    /// Disabling normalization reports all text exactly as written, for lossless processing.
+    /// Normalization is enabled by default.
+    pub fn set_normalization(&mut self, normalize: bool) {
+        self.config.normalize = normalize;
+    }
+
+    /// Sets the resolver consulted for named entities that are neither predefined
+    /// nor declared in the DTD
+    ///
+    /// Without a resolver such references are reported as `ParserErrorKind::InvalidEntity`.
+    pub fn set_entity_resolver<R: EntityResolver + Send + 'static>(&mut self, resolver: R) {
+        self.entities.resolver = Some(Box::new(resolver));
+    }
+
+    /// Sets the maximum nesting depth of references to entities declared in the DTD
+    ///
+    /// Exceeding it is reported as `ParserErrorKind::EntityNestingTooDeep`.
+    /// This also catches recursive entities. The default is 16.
+    pub fn set_max_entity_depth(&mut self, depth: usize) {
+        self.entities.max_depth = depth;
+    }
+
+    /// Sets the maximum size in bytes of the replacement text of entities declared in the DTD,
+    /// summed over all references expanded in the document
+    ///
+    /// Exceeding it is reported as `ParserErrorKind::EntityExpansionTooLarge`.
+    /// The default is 10 MiB.
+    pub fn set_max_entity_expansion(&mut self, size: usize) {
+        self.entities.max_expansion = size;
+    }
+
+    /// Signals that all input has been fed to the parser
+    ///
+    /// Iterating over the parser afterwards produces any character data still buffered.
+    /// If the document is incomplete, a `ParserErrorKind::UnexpectedEof` error is produced,
+    /// naming the construct left unterminated and the elements left open.
+    /// In strict mode a document without a root element is reported the same way.
+    /// Outside of strict and lenient mode an end tag only closes the innermost open element
+    /// if its name matches, so elements left open by mismatched end tags are reported too.
+    /// No more data should be fed to the parser after calling this.
+    pub fn finish(&mut self) {
+        self.finished = true;
+        self.decoder.finish(&mut self.data);
+    }
+
+    /// Parses the next event, telling apart whether more input is needed or
+    /// the document is complete if there is none
+    ///
+    /// `EndOfDocument` is returned once all input has been parsed after either the root element
+    /// was closed, or `finish()` was called. More events may still follow if more data is fed.
+    /// After an error has been returned, `EndOfDocument` is returned as well.
+    ///
+    /// ~~~
+    /// use xml::{Parser, Pull};
+    ///
+    /// let mut p = Parser::new();
+    /// p.feed_str("<a>");
+    /// assert!(match p.pull() {
+    ///     Ok(Pull::Event(_)) => true,
+    ///     _ => false,
+    /// });
+    /// assert_eq!(p.pull(), Ok(Pull::NeedMoreInput));
+    /// p.feed_str("</a>");
+    /// assert!(match p.pull() {
+    ///     Ok(Pull::Event(_)) => true,
+    ///     _ => false,
+    /// });
+    /// assert_eq!(p.pull(), Ok(Pull::EndOfDocument));
+    /// ~~~
+    pub fn pull(&mut self) -> Result<Pull, ParserError> {
+        match self.next() {
+            Some(Ok(event)) => Ok(Pull::Event(event)),
+            Some(Err(err)) => Err(err),
+            None => match self.state() {
+                ParserState::BetweenDocuments if self.seen_root => Ok(Pull::EndOfDocument),
+                ParserState::Failed => Ok(Pull::EndOfDocument),
+                _ if self.finished => Ok(Pull::EndOfDocument),
+                _ => Ok(Pull::NeedMoreInput),
+            },
+        }
+    }
+
+    /// Returns where in the document the parser currently is
+    ///
+    /// This reflects the data parsed so far, i.e. after iterating over the parser
+    /// until no more events are produced it describes the state at the end of the fed data.
+    pub fn state(&self) -> ParserState {
         if self.has_error {
-            return None;
+            return ParserState::Failed;
+        }
+        match self.construct() {
+            Some(construct) => ParserState::InMarkup(construct),
+            None if self.open_ends.is_empty() => ParserState::BetweenDocuments,
+            None => ParserState::InText,
         }
+    }
 
-        loop {
-            let c = match self.data.pop_front() {
-                Some(c) => c,
-                None => return None,
-            };
+    /// Returns the part of the input the last event was parsed from
+    ///
+    /// The span of a tag includes its delimiters, an empty-element tag such as `<a/>` is the
+    /// span of both its `ElementStart` and `ElementEnd` events. The span of `Characters`
+    /// covers the text as written, including entity references. After an error
+    /// the span covers the construct being parsed up to the point the error occurred.
+    ///
+    /// ~~~
+    /// use xml::{Event, Parser};
+    ///
+    /// let mut p = Parser::new();
+    /// p.feed_str("<a>\n  <b x='1'/></a>");
+    /// p.nth(1);
+    /// assert!(match p.next() {
+    ///     Some(Ok(Event::ElementStart(_))) => true,
+    ///     _ => false,
+    /// });
+    /// let span = p.span();
+    /// assert_eq!((span.start.offset, span.end.offset), (6, 16));
+    /// assert_eq!((span.start.line, span.start.col), (2, 3));
+    /// ~~~
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// Returns the encoding of data fed through `feed_bytes()`, once it has been detected
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.decoder.encoding()
+    }
+
+    /// Returns the namespace bound to a prefix at the last event,
+    /// or the default namespace if `prefix` is `None`
+    ///
+    /// The bindings declared on an element are in scope from its `ElementStart`
+    /// up to and including its `ElementEnd` event.
+    ///
+    /// ~~~
+    /// use xml::{Event, Parser};
+    ///
+    /// let mut p = Parser::new();
+    /// p.feed_str("<a xmlns:foo='urn:foo' type='foo:Bar'/>");
+    /// if let Some(Ok(Event::ElementStart(tag))) = p.next() {
+    ///     let value = &tag.attributes[&("type".to_owned(), None)];
+    ///     let (prefix, _) = value.split_at(value.find(':').unwrap());
+    ///     assert_eq!(p.namespace_for_prefix(Some(prefix)), Some("urn:foo"));
+    ///     assert_eq!(p.namespace_for_prefix(None), None);
+    /// }
+    /// ~~~
+    pub fn namespace_for_prefix(&self, prefix: Option<&str>) -> Option<&str> {
+        // Bindings are stored as a stack, we start searching at the innermost binding
+        // and traverse outwards until the prefix is found.
+        match self.binding(prefix) {
+            Some((_, namespace, _)) if !namespace.is_empty() => Some(namespace),
+            _ => None,
+        }
+    }
 
-            if c == '\n' {
-                self.line += 1;
-                self.col = 0;
+    /// Returns the namespace bindings in scope at the last event as (prefix, namespace) pairs,
+    /// see `namespace_for_prefix()`
+    ///
+    /// Each prefix is listed once, outermost declarations first.
+    /// This includes the predefined `xml` and `xmlns` prefixes.
+    pub fn in_scope_namespaces(&self) -> Vec<(Option<&str>, &str)> {
+        let mut bindings: Vec<(Option<&str>, &str)> = Vec::new();
+        for (prefix, namespace, _) in &self.namespaces {
+            let prefix = if prefix.is_empty() {
+                None
             } else {
-                self.col += 1;
+                Some(&prefix[..])
+            };
+            bindings.retain(|&(pre, _)| pre != prefix);
+            if !namespace.is_empty() {
+                bindings.push((prefix, namespace));
             }
+        }
+        bindings
+    }
 
-            match self.parse_character(c) {
-                Ok(None) => continue,
-                Ok(Some(event)) => {
-                    return Some(Ok(event));
-                }
-                Err(e) => {
-                    self.has_error = true;
-                    return Some(Err(e));
-                }
-            }
+    // Size of the input buffered for the current construct, including input not parsed yet
+    fn buffered(&self) -> usize {
+        self.base + self.data.len() - self.mark
+    }
+
+    // Number of bytes that can be fed without exceeding the limit on buffered input
+    pub(crate) fn buffer_capacity(&self) -> usize {
+        self.config.max_buffer_size.saturating_sub(self.buffered())
+    }
+
+    // Discard input that is no longer needed.
+    // This only happens once it makes up at least half of the buffer,
+    // so that the cost of moving the remaining data is amortized.
+    fn compact(&mut self) {
+        let unneeded = self.mark - self.base;
+        if unneeded > 0 && unneeded >= self.data.len() / 2 {
+            self.data.drain(..unneeded);
+            self.base = self.mark;
         }
     }
 }
 
+impl Iterator for Parser {
+    type Item = Result<Event, ParserError>;
+
+    fn next(&mut self) -> Option<Result<Event, ParserError>> {
+        // Parse from the buffer moved out of `self`, so events can borrow from it
+        let data = mem::take(&mut self.data);
+        let event = self
+            .next_event(&data)
+            .map(|result| result.map(BorrowedEvent::into_owned));
+        self.data = data;
+        event
+    }
+}
+
+/// A XML parser producing `BorrowedEvent`s for a complete document held in memory
+///
+/// Names, as well as text, attribute values and namespaces not containing any entities,
+/// are returned as slices of the input instead of being copied.
+/// Only namespaces bound by the `ParserConfig` are copied.
+/// As the input is complete, an incomplete document is reported as an error,
+/// as if `Parser::finish()` had been called.
+///
+/// ~~~
+/// use xml::{BorrowedEvent, BorrowedParser};
+///
+/// let p = BorrowedParser::new("<a href='http://rust-lang.org'>Rust</a>");
+/// for event in p {
+///     match event {
+///        Ok(BorrowedEvent::Characters(text)) => assert_eq!(text, "Rust"),
+///        // [...]
+///        _ => ()
+///     }
+/// }
+/// ~~~
+pub struct BorrowedParser<'a> {
+    input: &'a str,
+    parser: Parser,
+}
+
+impl<'a> BorrowedParser<'a> {
+    /// Returns a new `BorrowedParser` for the given input
+    pub fn new(input: &'a str) -> BorrowedParser<'a> {
+        BorrowedParser::with_config(input, ParserConfig::new())
+    }
+
+    /// Returns a new `BorrowedParser` for the given input using the given configuration
+    pub fn with_config(input: &'a str, config: ParserConfig) -> BorrowedParser<'a> {
+        let mut parser = Parser::with_config(config);
+        parser.finish();
+        BorrowedParser { input, parser }
+    }
+}
+
+impl<'a> BorrowedParser<'a> {
+    /// Returns the part of the input the last event was parsed from,
+    /// see `Parser::span()`
+    pub fn span(&self) -> Span {
+        self.parser.span()
+    }
+
+    /// Returns the namespace bound to a prefix at the last event,
+    /// see `Parser::namespace_for_prefix()`
+    pub fn namespace_for_prefix(&self, prefix: Option<&str>) -> Option<&str> {
+        self.parser.namespace_for_prefix(prefix)
+    }
+
+    /// Returns the namespace bindings in scope at the last event,
+    /// see `Parser::in_scope_namespaces()`
+    pub fn in_scope_namespaces(&self) -> Vec<(Option<&str>, &str)> {
+        self.parser.in_scope_namespaces()
+    }
+}
+
+impl<'a> Iterator for BorrowedParser<'a> {
+    type Item = Result<BorrowedEvent<'a>, ParserError>;
+
+    fn next(&mut self) -> Option<Result<BorrowedEvent<'a>, ParserError>> {
+        self.parser.next_event(self.input)
+    }
+}
+
 #[inline]
 // Parse a QName to get Prefix and LocalPart
-fn parse_qname(mut qname: String) -> (Option<String>, String) {
+fn parse_qname(qname: &str) -> (Option<&str>, &str) {
     if let Some(i) = qname.find(':') {
-        let local = qname.split_off(i + 1);
-        qname.pop();
-        (Some(qname), local)
+        (Some(&qname[..i]), &qname[i + 1..])
     } else {
         (None, qname)
     }
 }
 
-fn unescape_owned(input: String) -> Result<String, String> {
-    if input.find('&').is_none() {
-        Ok(input)
-    } else {
-        unescape(&input)
-    }
+#[inline]
+fn is_whitespace(b: u8) -> bool {
+    b == b' ' || b == b'\t' || b == b'\r' || b == b'\n'
 }
 
-impl Parser {
-    // Get the namespace currently bound to a prefix.
-    // Bindings are stored as a stack of HashMaps, we start searching in the top most HashMap
-    // and traverse down until the prefix is found.
-    fn namespace_for_prefix(&self, prefix: &str) -> Option<String> {
-        for ns in self.namespaces.iter().rev() {
-            if let Some(namespace) = ns.get(prefix) {
-                if namespace.is_empty() {
-                    return None;
-                }
-                return Some(namespace.clone());
-            }
-        }
-        None
+// Whether `c` may start a name
+fn is_name_start_char(c: char) -> bool {
+    match c {
+        ':'
+        | 'A'..='Z'
+        | '_'
+        | 'a'..='z'
+        | '\u{C0}'..='\u{D6}'
+        | '\u{D8}'..='\u{F6}'
+        | '\u{F8}'..='\u{2FF}'
+        | '\u{370}'..='\u{37D}'
+        | '\u{37F}'..='\u{1FFF}'
+        | '\u{200C}'..='\u{200D}'
+        | '\u{2070}'..='\u{218F}'
+        | '\u{2C00}'..='\u{2FEF}'
+        | '\u{3001}'..='\u{D7FF}'
+        | '\u{F900}'..='\u{FDCF}'
+        | '\u{FDF0}'..='\u{FFFD}'
+        | '\u{10000}'..='\u{EFFFF}' => true,
+        _ => false,
     }
+}
 
-    fn take_buf(&mut self) -> String {
-        self.buf.split_off(0)
+// Whether `c` may appear in a name after the first character
+fn is_name_char(c: char) -> bool {
+    match c {
+        '-' | '.' | '0'..='9' | '\u{B7}' | '\u{300}'..='\u{36F}' | '\u{203F}'..='\u{2040}' => true,
+        c => is_name_start_char(c),
     }
+}
 
-    fn error(&self, kind: ParserErrorKind) -> Result<Option<Event>, ParserError> {
-        Err(ParserError {
-            line: self.line,
-            col: self.col,
-            kind,
-        })
+// Whether `name` matches the Name production, which is the same for XML 1.0 and 1.1
+pub(crate) fn is_name(name: &str) -> bool {
+    let ascii = name.bytes().enumerate().all(|(idx, b)| match b {
+        b':' | b'A'..=b'Z' | b'_' | b'a'..=b'z' => true,
+        b'-' | b'.' | b'0'..=b'9' => idx > 0,
+        _ => false,
+    });
+    if ascii && !name.is_empty() {
+        return true;
     }
 
-    fn parse_character(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        // println(fmt!("Now in state: %?", self.st));
-        match self.st {
-            State::OutsideTag => self.outside_tag(c),
-            State::TagOpened => self.tag_opened(c),
-            State::InProcessingInstructions => self.in_processing_instructions(c),
-            State::InTagName => self.in_tag_name(c),
-            State::InCloseTagName => self.in_close_tag_name(c),
-            State::InTag => self.in_tag(c),
-            State::InAttrName => self.in_attr_name(c),
-            State::InAttrValue => self.in_attr_value(c),
-            State::ExpectDelimiter => self.expect_delimiter(c),
-            State::ExpectClose => self.expect_close(c),
-            State::ExpectSpaceOrClose => self.expect_space_or_close(c),
-            State::InExclamationMark => self.in_exclamation_mark(c),
-            State::InCDATAOpening => self.in_cdata_opening(c),
-            State::InCDATA => self.in_cdata(c),
-            State::InCommentOpening => self.in_comment_opening(c),
-            State::InComment1 => self.in_comment1(c),
-            State::InComment2 => self.in_comment2(c),
-            State::InDoctype => self.in_doctype(c),
-        }
+    let mut chars = name.chars();
+    chars.next().map_or(false, is_name_start_char) && chars.all(is_name_char)
+}
+
+// Whether `c` matches the Char production, i.e. may be included using a character reference
+pub(crate) fn is_char(c: char, xml11: bool) -> bool {
+    match c {
+        '\t'
+        | '\n'
+        | '\r'
+        | '\u{20}'..='\u{D7FF}'
+        | '\u{E000}'..='\u{FFFD}'
+        | '\u{10000}'..='\u{10FFFF}' => true,
+        '\u{1}'..='\u{1F}' => xml11,
+        _ => false,
     }
+}
 
-    // Outside any tag, or other construct
-    // '<' => TagOpened, producing Event::Characters
-    fn outside_tag(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        match c {
-            '<' if self.buf.is_empty() => self.st = State::TagOpened,
-            '<' => {
-                self.st = State::TagOpened;
-                let buf = match unescape_owned(self.take_buf()) {
-                    Ok(unescaped) => unescaped,
-                    Err(_) => return self.error(ParserErrorKind::InvalidEntity),
-                };
-                return Ok(Some(Event::Characters(buf)));
+// Returns the offset of the first character that may not appear directly in a document.
+// XML 1.1 additionally requires most C1 control characters to be written as references.
+fn find_invalid_char(text: &str, xml11: bool) -> Option<usize> {
+    const LOW: u64 = 0x0101_0101_0101_0101;
+    const HIGH: u64 = 0x8080_8080_8080_8080;
+    let bytes = text.as_bytes();
+    let mut idx = 0;
+    while idx < bytes.len() {
+        // Skip blocks of eight bytes of printable ASCII at once
+        if idx + 8 <= bytes.len() {
+            let mut block = [0; 8];
+            block.copy_from_slice(&bytes[idx..idx + 8]);
+            let x = u64::from_le_bytes(block);
+            let below_space = x.wrapping_sub(LOW * 0x20) & !x & HIGH;
+            let above_tilde = (x.wrapping_add(LOW) | x) & HIGH;
+            if below_space | above_tilde == 0 {
+                idx += 8;
+                continue;
             }
-            _ => self.buf.push(c),
         }
-        Ok(None)
-    }
 
-    // Character following a '<', starting a tag or other construct
-    // '?' => InProcessingInstructions
-    // '!' => InExclamationMark
-    // '/' => InCloseTagName
-    //  _  => InTagName
-    fn tag_opened(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        self.st = match c {
-            '?' => State::InProcessingInstructions,
-            '!' => State::InExclamationMark,
-            '/' => State::InCloseTagName,
-            _ => {
-                self.buf.push(c);
-                State::InTagName
-            }
+        let invalid = match bytes[idx] {
+            b'\t' | b'\n' | b'\r' => false,
+            0x00..=0x1F => true,
+            0x7F => xml11,
+            // U+0080 to U+009F, except U+0085
+            0xC2 => xml11 && bytes[idx + 1] <= 0x9F && bytes[idx + 1] != 0x85,
+            // U+FFFE and U+FFFF
+            0xEF => bytes[idx + 1] == 0xBF && bytes[idx + 2] >= 0xBE,
+            _ => false,
         };
-        Ok(None)
-    }
-
-    // Inside a processing instruction
-    // '?' '>' => OutsideTag, producing PI
-    fn in_processing_instructions(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        match c {
-            '?' => {
-                self.level = 1;
-                self.buf.push(c);
-            }
-            '>' if self.level == 1 => {
-                self.level = 0;
-                self.st = State::OutsideTag;
-                let _ = self.buf.pop();
-                let buf = self.take_buf();
-                return Ok(Some(Event::PI(buf)));
-            }
-            _ => self.buf.push(c),
+        if invalid {
+            return Some(idx);
         }
-        Ok(None)
+        idx += 1;
     }
+    None
+}
 
-    // Inside a tag name (opening tag)
-    // '/' => ExpectClose, producing Event::ElementStart
-    // '>' => OutsideTag, producing Event::ElementStart
-    // ' ' or '\t' or '\r' or '\n' => InTag
-    fn in_tag_name(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        match c {
-            '/' | '>' => {
-                let (prefix, name) = parse_qname(self.take_buf());
-                let ns = match prefix {
-                    None => self.namespace_for_prefix(""),
-                    Some(ref pre) => match self.namespace_for_prefix(pre) {
-                        None => return self.error(ParserErrorKind::UnboundNsPrefixInTagName),
-                        ns => ns,
-                    },
-                };
+fn trim_whitespace_start(input: &str) -> &str {
+    input.trim_start_matches(|c| c == ' ' || c == '\t' || c == '\r' || c == '\n')
+}
 
-                self.namespaces.push(HashMap::new());
-                self.st = if c == '/' {
-                    self.name = Some((prefix.clone(), name.clone()));
-                    State::ExpectClose
-                } else {
-                    State::OutsideTag
-                };
+fn trim_whitespace(input: &str) -> &str {
+    input.trim_matches(|c| c == ' ' || c == '\t' || c == '\r' || c == '\n')
+}
 
-                return Ok(Some(Event::ElementStart(StartTag {
-                    name,
-                    ns,
-                    prefix,
-                    attributes: AttrMap::new(),
-                })));
-            }
-            ' ' | '\t' | '\r' | '\n' => {
-                self.namespaces.push(HashMap::new());
-                self.name = Some(parse_qname(self.take_buf()));
-                self.st = State::InTag;
-            }
-            _ => self.buf.push(c),
-        }
-        Ok(None)
+// Parse a quoted literal, which has to be preceded by whitespace.
+// Returns the literal's content and the remaining input.
+fn parse_literal(input: &str) -> Option<(&str, &str)> {
+    let trimmed = trim_whitespace_start(input);
+    if trimmed.len() == input.len() {
+        return None;
     }
+    let delim = match trimmed.chars().next() {
+        Some(c @ '"') | Some(c @ '\'') => c,
+        _ => return None,
+    };
+    let end = trimmed[1..].find(delim)? + 1;
+    Some((&trimmed[1..end], &trimmed[end + 1..]))
+}
 
-    // Inside a tag name (closing tag)
-    // '>' => OutsideTag, producing ElementEnd
-    // ' ' or '\t' or '\r' or '\n' => ExpectSpaceOrClose, producing ElementEnd
-    fn in_close_tag_name(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        match c {
-            ' ' | '\t' | '\r' | '\n' | '>' => {
-                let (prefix, name) = parse_qname(self.take_buf());
-
-                let ns = match prefix {
-                    None => self.namespace_for_prefix(""),
-                    Some(ref pre) => match self.namespace_for_prefix(pre) {
-                        None => return self.error(ParserErrorKind::UnboundNsPrefixInTagName),
-                        ns => ns,
-                    },
-                };
+// Parse a pseudo-attribute called `name`, which has to be preceded by whitespace.
+// Returns its value and the remaining input.
+fn parse_pseudo_attr<'a>(input: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+    let trimmed = trim_whitespace_start(input);
+    if trimmed.len() == input.len() || !trimmed.starts_with(name) {
+        return None;
+    }
+    let rest = trim_whitespace_start(&trimmed[name.len()..]);
+    if !rest.starts_with('=') {
+        return None;
+    }
+    let rest = trim_whitespace_start(&rest[1..]);
+    let delim = match rest.chars().next() {
+        Some(c @ '"') | Some(c @ '\'') => c,
+        _ => return None,
+    };
+    let end = rest[1..].find(delim)? + 1;
+    Some((&rest[1..end], &rest[end + 1..]))
+}
 
-                self.namespaces.pop();
-                self.st = if c == '>' {
-                    State::OutsideTag
-                } else {
-                    State::ExpectSpaceOrClose
-                };
+// Parse the content of an XML declaration following the "xml" target
+fn parse_xml_decl(decl: &str) -> Option<BorrowedXmlDecl<'_>> {
+    let (version, rest) = parse_pseudo_attr(decl, "version")?;
+    if !version.starts_with("1.")
+        || version.len() == 2
+        || !version[2..].bytes().all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
 
-                Ok(Some(Event::ElementEnd(EndTag { name, ns, prefix })))
-            }
-            _ => {
-                self.buf.push(c);
-                Ok(None)
+    let (encoding, rest) = match parse_pseudo_attr(rest, "encoding") {
+        Some((encoding, rest)) => {
+            let mut chars = encoding.chars();
+            let valid = chars.next().map_or(false, |c| c.is_ascii_alphabetic())
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-');
+            if !valid {
+                return None;
             }
+            (Some(Cow::Borrowed(encoding)), rest)
         }
+        None => (None, rest),
+    };
+
+    let (standalone, rest) = match parse_pseudo_attr(rest, "standalone") {
+        Some(("yes", rest)) => (Some(true), rest),
+        Some(("no", rest)) => (Some(false), rest),
+        Some(_) => return None,
+        None => (None, rest),
+    };
+
+    if !trim_whitespace_start(rest).is_empty() {
+        return None;
     }
 
-    // Inside a tag, parsing attributes
-    // '/' => ExpectClose, producing StartTag
-    // '>' => OutsideTag, producing StartTag
-    // ' ' or '\t' or '\r' or '\n' => InAttrName
-    fn in_tag(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        match c {
-            '/' | '>' => {
-                let attributes = mem::take(&mut self.attributes);
-                let (prefix, name) = self
-                    .name
-                    .take()
-                    .expect("Internal error: No element name set");
-                let ns = match prefix {
-                    None => self.namespace_for_prefix(""),
-                    Some(ref pre) => match self.namespace_for_prefix(pre) {
-                        None => return self.error(ParserErrorKind::UnboundNsPrefixInTagName),
-                        ns => ns,
-                    },
-                };
+    Some(BorrowedXmlDecl {
+        version: Cow::Borrowed(version),
+        encoding,
+        standalone,
+    })
+}
 
-                let mut attributes_map: AttrMap<(String, Option<String>), String> = AttrMap::new();
-
-                // At this point attribute namespaces are really just prefixes,
-                // map them to the actual namespace
-                for (name, ns, value) in attributes {
-                    let ns = match ns {
-                        None => None,
-                        Some(ref prefix) => match self.namespace_for_prefix(prefix) {
-                            None => {
-                                return self.error(ParserErrorKind::UnboundNsPrefixInAttributeName)
-                            }
-                            ns => ns,
-                        },
-                    };
-                    if attributes_map.insert((name, ns), value).is_some() {
-                        return self.error(ParserErrorKind::DuplicateAttribute);
-                    }
-                }
+// Parse the content of a doctype declaration following the DOCTYPE keyword
+fn parse_doctype(decl: &str) -> Option<BorrowedDoctype<'_>> {
+    let name_len = decl
+        .find(|c| match c {
+            '[' | '"' | '\'' | ' ' | '\t' | '\r' | '\n' => true,
+            _ => false,
+        })
+        .unwrap_or(decl.len());
+    let name = &decl[..name_len];
+    if !is_name(name) {
+        return None;
+    }
+    let mut rest = trim_whitespace_start(&decl[name_len..]);
 
-                self.st = if c == '/' {
-                    self.name = Some((prefix.clone(), name.clone()));
-                    State::ExpectClose
-                } else {
-                    State::OutsideTag
-                };
+    let mut public_id = None;
+    let mut system_id = None;
+    if rest.starts_with("SYSTEM") {
+        let (system, r) = parse_literal(&rest[6..])?;
+        system_id = Some(Cow::Borrowed(system));
+        rest = r;
+    } else if rest.starts_with("PUBLIC") {
+        let (public, r) = parse_literal(&rest[6..])?;
+        let (system, r) = parse_literal(r)?;
+        public_id = Some(Cow::Borrowed(public));
+        system_id = Some(Cow::Borrowed(system));
+        rest = r;
+    }
 
-                return Ok(Some(Event::ElementStart(StartTag {
-                    name,
-                    ns,
-                    prefix,
-                    attributes: attributes_map,
-                })));
-            }
-            ' ' | '\t' | '\r' | '\n' => (),
-            _ => {
-                self.buf.push(c);
-                self.st = State::InAttrName;
-            }
-        }
-        Ok(None)
+    let mut rest = trim_whitespace_start(rest);
+    let mut internal_subset = None;
+    if rest.starts_with('[') {
+        let end = rest.rfind(']')?;
+        internal_subset = Some(Cow::Borrowed(&rest[1..end]));
+        rest = &rest[end + 1..];
     }
 
-    // Inside an attribute name
-    // '=' => ExpectDelimiter
-    fn in_attr_name(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        match c {
-            '=' => {
-                self.level = 0;
-                self.attr = Some(parse_qname(self.take_buf()));
-                self.st = State::ExpectDelimiter;
+    if !trim_whitespace_start(rest).is_empty() {
+        return None;
+    }
+
+    Some(BorrowedDoctype {
+        name: Cow::Borrowed(name),
+        public_id,
+        system_id,
+        internal_subset,
+    })
+}
+
+// Number of characters in a UTF-8 encoded byte sequence
+#[inline]
+fn count_chars(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&b| b & 0xC0 != 0x80).count()
+}
+
+// Normalize line breaks in `text` to '\n', and in attribute values all whitespace to ' '
+fn normalize_text(text: &str, xml11: bool, attr: bool) -> Cow<'_, str> {
+    let affected = text.bytes().any(|b| match b {
+        b'\r' => true,
+        b'\t' | b'\n' => attr,
+        // Lead bytes of U+0085 and U+2028
+        0xC2 | 0xE2 => xml11,
+        _ => false,
+    });
+    if !affected {
+        return Cow::Borrowed(text);
+    }
+
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\r' => {
+                match chars.peek() {
+                    Some('\n') => {
+                        chars.next();
+                    }
+                    Some('\u{85}') if xml11 => {
+                        chars.next();
+                    }
+                    _ => (),
+                }
+                '\n'
             }
-            ' ' | '\t' | '\r' | '\n' => self.level = 1,
-            _ if self.level == 0 => self.buf.push(c),
-            _ => return self.error(ParserErrorKind::SpaceInAttributeName),
+            '\u{85}' | '\u{2028}' if xml11 => '\n',
+            c => c,
+        };
+        if attr && (c == '\n' || c == '\t') {
+            result.push(' ');
+        } else {
+            result.push(c);
         }
-        Ok(None)
     }
+    Cow::Owned(result)
+}
 
-    // Inside an attribute value
-    // delimiter => InTag, adds attribute
-    fn in_attr_value(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        if c == self
-            .delim
-            .expect("Internal error: In attribute value, but no delimiter set")
-        {
-            self.delim = None;
-            self.st = State::InTag;
-            let attr = self.attr.take();
-            let (prefix, name) =
-                attr.expect("Internal error: In attribute value, but no attribute name set");
-            let value = match unescape_owned(self.take_buf()) {
-                Ok(unescaped) => unescaped,
-                Err(_) => return self.error(ParserErrorKind::InvalidEntity),
-            };
-
-            let last = self
+// The namespace bindings in scope outside of the document, the standard ones first.
+// Those of the configuration are only used with namespace processing.
+fn initial_namespaces(config: &ParserConfig) -> Vec<(String, String, Option<Range<usize>>)> {
+    let mut ns = vec![
+        (
+            "xml".to_owned(),
+            "http://www.w3.org/XML/1998/namespace".to_owned(),
+            None,
+        ),
+        (
+            "xmlns".to_owned(),
+            "http://www.w3.org/2000/xmlns/".to_owned(),
+            None,
+        ),
+    ];
+    if config.namespace_aware {
+        ns.extend(
+            config
                 .namespaces
-                .last_mut()
-                .expect("Internal error: Empty namespace stack");
-            match prefix {
-                None if name == "xmlns" => {
-                    last.insert(String::new(), value.clone());
-                }
-                Some(ref prefix) if prefix == "xmlns" => {
-                    last.insert(name.clone(), value.clone());
-                }
-                _ => (),
+                .iter()
+                .map(|(prefix, namespace)| (prefix.clone(), namespace.clone(), None)),
+        );
+    }
+    ns
+}
+
+impl Parser {
+    // Split a qualified name into prefix and local name.
+    // Without namespace processing names are kept as written.
+    fn split_name<'x>(&self, qname: &'x str) -> (Option<&'x str>, &'x str) {
+        if self.config.namespace_aware {
+            parse_qname(qname)
+        } else {
+            (None, qname)
+        }
+    }
+
+    // Open a new scope for namespace bindings
+    fn push_ns_scope(&mut self) {
+        if self.config.namespace_aware {
+            self.ns_scopes.push(self.namespaces.len());
+        }
+    }
+
+    // Remove all bindings of the innermost scope
+    fn pop_ns_scope(&mut self) {
+        if let Some(len) = self.ns_scopes.pop() {
+            self.namespaces.truncate(len);
+        }
+    }
+
+    // Name of the innermost open element
+    fn current_element(&self) -> Option<&str> {
+        let end = *self.open_ends.last()?;
+        let start = match self.open_ends.len() {
+            1 => 0,
+            len => self.open_ends[len - 2],
+        };
+        Some(&self.open_names[start..end])
+    }
+
+    // Record an element as open
+    fn push_element(&mut self, name: &str) {
+        self.open_names.push_str(name);
+        self.open_ends.push(self.open_names.len());
+    }
+
+    // Remove the innermost open element
+    fn pop_element(&mut self) {
+        self.open_ends.pop();
+        let len = self.open_ends.last().cloned().unwrap_or(0);
+        self.open_names.truncate(len);
+    }
+
+    // Innermost binding of a prefix
+    fn binding(&self, prefix: Option<&str>) -> Option<&(String, String, Option<Range<usize>>)> {
+        let prefix = prefix.unwrap_or("");
+        self.namespaces
+            .iter()
+            .rev()
+            .find(|(pre, _, _)| pre == prefix)
+    }
+
+    // Resolve a prefix to a namespace.
+    // It is borrowed from the input if it was declared there and has not been discarded yet.
+    fn resolve_prefix<'x>(&self, data: &'x str, prefix: Option<&str>) -> Option<Cow<'x, str>> {
+        match self.binding(prefix) {
+            Some((_, namespace, _)) if namespace.is_empty() => None,
+            Some((_, _, Some(range))) if range.start >= self.base => {
+                Some(Cow::Borrowed(self.slice(data, range.clone())))
+            }
+            Some((_, namespace, _)) => Some(Cow::Owned(namespace.clone())),
+            None => None,
+        }
+    }
+
+    // Resolve the prefix of an element name to a namespace
+    fn element_namespace<'x>(
+        &mut self,
+        data: &'x str,
+        prefix: Option<&str>,
+    ) -> Result<Option<Cow<'x, str>>, ParserError> {
+        match self.resolve_prefix(data, prefix) {
+            None if prefix.is_some() => self
+                .report(ParserErrorKind::UnboundNsPrefixInTagName)
+                .map(|_| None),
+            ns => Ok(ns),
+        }
+    }
+
+    // Names of the open elements, outermost first
+    fn open_elements(&self) -> Vec<&str> {
+        let mut start = 0;
+        self.open_ends
+            .iter()
+            .map(|&end| {
+                let name = &self.open_names[start..end];
+                start = end;
+                name
+            })
+            .collect()
+    }
+
+    // Forget the state of the previous document, starting a new one at the current offset
+    fn start_document(&mut self) {
+        self.seen_root = false;
+        self.doc_start = self.pos;
+        self.xml11 = false;
+        self.entities.clear();
+        // Check the remaining input again, following XML 1.0 rules
+        self.end = self.pos;
+        self.char_error = false;
+    }
+
+    // Record that an element has been closed, ending the document if it was the root
+    // in concatenated documents mode
+    fn element_closed(&mut self) {
+        self.closed_scope = true;
+        if self.open_ends.is_empty() && self.config.concatenated_documents {
+            self.pending_document_end = true;
+        }
+    }
+
+    // Produce ElementEnd for the innermost open element, closing it
+    fn close_element<'x>(&mut self) -> BorrowedEvent<'x> {
+        let qname = self
+            .current_element()
+            .expect("Internal error: No element to close")
+            .to_owned();
+        let (prefix, local) = self.split_name(&qname);
+        let ns = self.namespace_for_prefix(prefix).map(str::to_owned);
+        let event = BorrowedEvent::ElementEnd(BorrowedEndTag {
+            name: Cow::Owned(local.to_owned()),
+            ns: ns.map(Cow::Owned),
+            prefix: prefix.map(|prefix| Cow::Owned(prefix.to_owned())),
+        });
+        self.pop_element();
+        self.element_closed();
+        event
+    }
+
+    // The input that has not been parsed yet
+    fn rest<'x>(&self, data: &'x str) -> &'x str {
+        &data[self.pos - self.base..self.end - self.base]
+    }
+
+    // Check newly available input for characters not allowed in XML.
+    // Parsing stops at the first one found.
+    fn check_chars(&mut self, data: &str) {
+        let data_end = self.base + data.len();
+        if self.char_error || self.end == data_end {
+            return;
+        }
+        match find_invalid_char(&data[self.end - self.base..], self.xml11) {
+            Some(idx) => {
+                self.end += idx;
+                self.char_error = true;
             }
+            None => self.end = data_end,
+        }
+    }
 
-            self.attributes.push((name, prefix, value));
+    // Check that the input in the given range is a valid name
+    fn check_name(&mut self, data: &str, name: Range<usize>) -> Result<(), ParserError> {
+        if is_name(self.slice(data, name)) {
+            Ok(())
         } else {
-            self.buf.push(c);
+            self.report(ParserErrorKind::InvalidName)
         }
-        Ok(None)
     }
 
-    // Looking for an attribute value delimiter
-    // '"' or '\'' => InAttrValue, sets delimiter
-    fn expect_delimiter(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        match c {
-            '"' | '\'' => {
-                self.delim = Some(c);
-                self.st = State::InAttrValue;
+    // The input in the given range of absolute offsets
+    fn slice<'x>(&self, data: &'x str, range: Range<usize>) -> &'x str {
+        &data[range.start - self.base..range.end - self.base]
+    }
+
+    // Normalize line breaks, and whitespace in attribute values, unless disabled
+    fn normalize<'x>(&self, text: &'x str, attr: bool) -> Cow<'x, str> {
+        if self.config.normalize {
+            normalize_text(text, self.xml11, attr)
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+
+    // Normalize and unescape character data or an attribute value
+    fn unescape<'x>(&mut self, text: &'x str, attr: bool) -> Result<Cow<'x, str>, ParserError> {
+        let text = self.normalize(text, attr);
+        let attr = attr && self.config.normalize;
+        let unescaped = match self.entities.unescape(&text, attr) {
+            Ok(Cow::Owned(unescaped)) => Some(unescaped),
+            Ok(Cow::Borrowed(_)) => None,
+            Err(kind) => {
+                self.report(kind)?;
+                Some(self.entities.unescape_lenient(&text, attr))
             }
-            ' ' | '\t' | '\r' | '\n' => (),
-            _ => return self.error(ParserErrorKind::UndelimitedAttribute),
+        };
+        Ok(match unescaped {
+            Some(unescaped) => Cow::Owned(unescaped),
+            None => text,
+        })
+    }
+
+    // The position of the next byte to parse
+    fn current_position(&self) -> Position {
+        Position {
+            offset: self.pos,
+            line: self.line,
+            col: self.col + 1,
         }
-        Ok(None)
     }
 
-    // Expect closing '>' of an empty-element tag (no whitespace allowed)
-    // '>' => OutsideTag
-    fn expect_close(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        match c {
-            '>' => {
-                self.st = State::OutsideTag;
-                let (prefix, name) = self
-                    .name
-                    .take()
-                    .expect("Internal error: No element name set");
-                let ns = match prefix {
-                    None => self.namespace_for_prefix(""),
-                    Some(ref pre) => match self.namespace_for_prefix(pre) {
-                        None => return self.error(ParserErrorKind::UnboundNsPrefixInTagName),
-                        ns => ns,
-                    },
-                };
-                self.namespaces.pop();
-                Ok(Some(Event::ElementEnd(EndTag { name, ns, prefix })))
+    // Mark the start of the next construct at the current position
+    fn set_mark(&mut self) {
+        self.mark = self.pos;
+        self.mark_position = self.current_position();
+    }
+
+    // Mark `len` bytes of input as parsed, keeping track of line and column
+    fn advance(&mut self, data: &str, len: usize) {
+        let start = self.pos - self.base;
+        let bytes = &data.as_bytes()[start..start + len];
+        match bytes.iter().rposition(|&b| b == b'\n' || b == b'\r') {
+            Some(last) => {
+                // "\r\n" counts as a single line break, even if split across calls
+                let mut last_cr = self.last_cr;
+                for &b in bytes {
+                    if b == b'\r' || (b == b'\n' && !last_cr) {
+                        self.line += 1;
+                    }
+                    last_cr = b == b'\r';
+                }
+                self.last_cr = last_cr;
+                self.col = count_chars(&bytes[last + 1..]) as u32;
             }
-            _ => self.error(ParserErrorKind::ExpectedTagClose),
+            None if len > 0 => {
+                self.last_cr = false;
+                self.col += count_chars(bytes) as u32;
+            }
+            None => (),
         }
+        self.pos += len;
     }
 
-    // Expect closing '>' of a start tag
-    // '>' => OutsideTag
-    fn expect_space_or_close(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        match c {
-            ' ' | '\t' | '\r' | '\n' => Ok(None),
-            '>' => {
-                self.st = State::OutsideTag;
-                Ok(None)
+    // Parse the next character of input
+    fn next_char(&mut self, data: &str) -> char {
+        let c = self
+            .rest(data)
+            .chars()
+            .next()
+            .expect("Internal error: No input left");
+        self.advance(data, c.len_utf8());
+        c
+    }
+
+    // Parse input up to and including the first byte matching `pred`, which is returned.
+    // If no byte matches all input is parsed and `None` is returned.
+    // `pred` must only match ASCII characters.
+    fn scan_until<F: Fn(u8) -> bool>(&mut self, data: &str, pred: F) -> Option<u8> {
+        let rest = self.rest(data);
+        match rest.bytes().position(pred) {
+            Some(len) => {
+                self.advance(data, len + 1);
+                Some(rest.as_bytes()[len])
+            }
+            None => {
+                self.advance(data, rest.len());
+                None
             }
-            _ => self.error(ParserErrorKind::ExpectedLwsOrTagClose),
         }
     }
 
-    // After an '!' trying to determine the type of the following construct
-    // '-' => InCommentOpening
-    // '[' => InCDATAOpening
-    // 'D' => InDoctype
-    fn in_exclamation_mark(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        self.st = match c {
-            '-' => State::InCommentOpening,
-            '[' => State::InCDATAOpening,
-            'D' => State::InDoctype,
-            _ => return self.error(ParserErrorKind::MalformedXml),
+    // Parse input like `scan_until()`, checking that the input parsed since `from`,
+    // excluding the matching byte, does not exceed `max` bytes
+    fn scan_limited<F: Fn(u8) -> bool, K: FnOnce() -> ParserErrorKind>(
+        &mut self,
+        data: &str,
+        pred: F,
+        from: usize,
+        max: usize,
+        kind: K,
+    ) -> Result<Option<u8>, ParserError> {
+        let found = self.scan_until(data, pred);
+        let end = if found.is_some() {
+            self.pos - 1
+        } else {
+            self.pos
         };
-        Ok(None)
+        self.check_size(end - from, max, kind)?;
+        Ok(found)
     }
 
-    // Opening sequence of Event::CDATA
-    // 'C' 'D' 'A' 'T' 'A' '[' => InCDATA
-    fn in_cdata_opening(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        static CDATA_PATTERN: [char; 6] = ['C', 'D', 'A', 'T', 'A', '['];
-        if c == CDATA_PATTERN[self.level as usize] {
-            self.level += 1;
+    // Check that a name, value or text of `size` bytes does not exceed the limit `max`,
+    // `kind` returns the error to report otherwise
+    fn check_size<K: FnOnce() -> ParserErrorKind>(
+        &self,
+        size: usize,
+        max: usize,
+        kind: K,
+    ) -> Result<(), ParserError> {
+        if size > max {
+            Err(self.make_error(kind()))
         } else {
-            return self.error(ParserErrorKind::InvalidCdataStart);
+            Ok(())
         }
+    }
 
-        if self.level == 6 {
-            self.level = 0;
-            self.st = State::InCDATA;
-        }
-        Ok(None)
+    // Return to character data after a construct has been parsed completely
+    fn leave_markup(&mut self) {
+        self.st = State::OutsideTag;
+        self.set_mark();
     }
 
-    // Inside CDATA
-    // ']' ']' '>' => OutsideTag, producing Event::CDATA
-    fn in_cdata(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        match c {
-            ']' => {
-                self.buf.push(c);
-                self.level += 1;
+    // Parse `data`, the input buffer, until an event is produced
+    fn next_event<'x>(&mut self, data: &'x str) -> Option<Result<BorrowedEvent<'x>, ParserError>> {
+        if self.has_error {
+            return None;
+        }
+        if self.closed_scope {
+            self.closed_scope = false;
+            self.pop_ns_scope();
+        }
+
+        loop {
+            if self.pending_document_end {
+                self.pending_document_end = false;
+                self.start_document();
+                self.follows_document = true;
+                let position = self.current_position();
+                self.span = Span {
+                    start: position,
+                    end: position,
+                };
+                return Some(Ok(BorrowedEvent::DocumentEnd));
             }
-            '>' if self.level >= 2 => {
-                self.st = State::OutsideTag;
-                self.level = 0;
-                let len = self.buf.len();
-                self.buf.truncate(len - 2);
-                let buf = self.take_buf();
-                return Ok(Some(Event::CDATA(buf)));
+            if self.pending_closes > 0 {
+                self.pending_closes -= 1;
+                let position = self.current_position();
+                self.span = Span {
+                    start: position,
+                    end: position,
+                };
+                return Some(Ok(self.close_element()));
             }
-            _ => {
-                self.buf.push(c);
-                self.level = 0;
+
+            self.check_chars(data);
+            // Skip a byte order mark at the start of a document,
+            // as the decoder does for input fed as bytes
+            if self.pos == self.doc_start && self.rest(data).starts_with('\u{feff}') {
+                self.pos += '\u{feff}'.len_utf8();
+                self.doc_start = self.pos;
+                self.set_mark();
+            }
+            let start = self.mark_position;
+            let result = if !self.rest(data).is_empty() {
+                if self.text_span.is_some() && !self.continues_text(data) {
+                    match self.flush_text() {
+                        Some(event) => return Some(Ok(event)),
+                        None => continue,
+                    }
+                }
+                self.parse_input(data)
+            } else if self.char_error {
+                let err = self.make_error(ParserErrorKind::InvalidChar);
+                if self.config.lenient {
+                    // Accept the character as written
+                    self.diagnostics.push(err);
+                    let len = data[self.end - self.base..]
+                        .chars()
+                        .next()
+                        .map_or(1, char::len_utf8);
+                    self.end += len;
+                    self.char_error = false;
+                    continue;
+                }
+                Err(err)
+            } else if let Some(kind) = self.decoder.error() {
+                Err(self.make_error(kind))
+            } else if self.buffer_exceeded {
+                Err(self.make_error(ParserErrorKind::InputBufferTooLarge))
+            } else if self.finished {
+                if self.text_span.is_some() && (self.construct().is_some() || self.mark == self.pos)
+                {
+                    match self.flush_text() {
+                        Some(event) => return Some(Ok(event)),
+                        None => continue,
+                    }
+                }
+                match self.end_of_input(data) {
+                    Ok(None) => return None,
+                    result => result,
+                }
+            } else {
+                return None;
+            };
+
+            let result = match result {
+                Ok(Some(event)) => {
+                    let end = match self.st {
+                        // The closing '>' of an empty-element tag is parsed after its start event
+                        State::ExpectClose => Position {
+                            offset: self.pos + 1,
+                            line: self.line,
+                            col: self.col + 2,
+                        },
+                        _ => self.mark_position,
+                    };
+                    self.span = Span { start, end };
+                    self.filter_event(event)
+                }
+                Ok(None) if self.pos - self.mark > self.config.max_buffer_size => {
+                    Err(self.make_error(ParserErrorKind::InputBufferTooLarge))
+                }
+                result => result,
+            };
+
+            match result {
+                Ok(None) => continue,
+                Ok(Some(event)) => return Some(Ok(event)),
+                Err(e) => {
+                    self.span = Span {
+                        start,
+                        end: self.current_position(),
+                    };
+                    let fatal = match e.kind {
+                        ParserErrorKind::InvalidUtf8
+                        | ParserErrorKind::InvalidUtf16
+                        | ParserErrorKind::UnsupportedEncoding
+                        | ParserErrorKind::NestingTooDeep
+                        | ParserErrorKind::TooManyAttributes
+                        | ParserErrorKind::NameTooLong
+                        | ParserErrorKind::AttributeValueTooLarge
+                        | ParserErrorKind::TextTooLarge
+                        | ParserErrorKind::InputBufferTooLarge => true,
+                        _ => false,
+                    };
+                    if self.config.lenient && !fatal {
+                        self.recover(data, &e.kind);
+                        self.diagnostics.push(e);
+                        continue;
+                    }
+                    self.has_error = true;
+                    return Some(Err(e));
+                }
             }
         }
-        Ok(None)
     }
 
-    // Opening sequence of a comment
-    // '-' => InComment1
-    fn in_comment_opening(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        if c == '-' {
-            self.st = State::InComment1;
-            self.level = 0;
-            Ok(None)
+    // Apply the configured handling of whitespace, comments and processing instructions.
+    // Returns `None` if the event is dropped, or buffered to be coalesced with other text.
+    fn filter_event<'x>(
+        &mut self,
+        event: BorrowedEvent<'x>,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        Ok(match event {
+            BorrowedEvent::Comment(_) if self.config.skip_comments => None,
+            BorrowedEvent::PI(_) if self.config.skip_processing_instructions => None,
+            BorrowedEvent::Characters(text) | BorrowedEvent::CDATA(text)
+                if self.config.coalesce_characters =>
+            {
+                self.check_size(
+                    self.text.len() + text.len(),
+                    self.config.max_text_size,
+                    || ParserErrorKind::TextTooLarge,
+                )?;
+                self.text.push_str(&text);
+                let start = self.text_span.map_or(self.span.start, |span| span.start);
+                self.text_span = Some(Span {
+                    start,
+                    end: self.span.end,
+                });
+                None
+            }
+            BorrowedEvent::Characters(text) => self.whitespace(text).map(BorrowedEvent::Characters),
+            event => Some(event),
+        })
+    }
+
+    // Produce the text buffered for coalescing
+    fn flush_text<'x>(&mut self) -> Option<BorrowedEvent<'x>> {
+        self.span = self.text_span.take()?;
+        let text = mem::take(&mut self.text);
+        self.whitespace(Cow::Owned(text))
+            .map(BorrowedEvent::Characters)
+    }
+
+    // Trim character data or drop it if it is whitespace only, as configured
+    fn whitespace<'x>(&self, text: Cow<'x, str>) -> Option<Cow<'x, str>> {
+        let trim = self.config.trim_whitespace;
+        if (trim || self.config.ignore_whitespace) && text.bytes().all(is_whitespace) {
+            return None;
+        }
+        if !trim {
+            return Some(text);
+        }
+        Some(match text {
+            Cow::Borrowed(text) => Cow::Borrowed(trim_whitespace(text)),
+            Cow::Owned(text) => {
+                let trimmed = trim_whitespace(&text);
+                if trimmed.len() == text.len() {
+                    Cow::Owned(text)
+                } else {
+                    Cow::Owned(trimmed.to_owned())
+                }
+            }
+        })
+    }
+
+    // Whether the construct starting at the current position continues a run of text
+    // to be coalesced, as far as can be told yet
+    fn continues_text(&self, data: &str) -> bool {
+        let next = self.rest(data).as_bytes()[0];
+        match self.st {
+            State::TagOpened => match next {
+                b'!' => true,
+                b'?' => self.config.skip_processing_instructions,
+                _ => false,
+            },
+            State::InExclamationMark => match next {
+                b'-' => self.config.skip_comments,
+                b'[' => true,
+                _ => false,
+            },
+            _ => true,
+        }
+    }
+
+    // Continue after an error in lenient mode,
+    // skipping the rest of the construct it occurred in
+    fn recover(&mut self, data: &str, kind: &ParserErrorKind) {
+        if let State::ExpectClose = self.st {
+            // The start of the empty element has been produced already
+            self.pending_closes += 1;
+        }
+        if self.ns_scopes.len() > self.open_ends.len() {
+            // The scope of an unfinished start tag
+            self.pop_ns_scope();
+        }
+        self.attributes.clear();
+        self.name = None;
+        self.attr = None;
+        self.delim = None;
+        self.level = 0;
+
+        if let ParserErrorKind::UnexpectedEof { .. } = *kind {
+            self.pending_closes = self.open_ends.len();
+            // A missing root element is only reported once
+            self.seen_root = true;
+            self.st = State::OutsideTag;
+        } else if self.pos > self.base && data.as_bytes()[self.pos - self.base - 1] == b'>' {
+            self.st = State::OutsideTag;
         } else {
-            self.error(ParserErrorKind::InvalidCommentStart)
+            self.st = State::SkipMarkup;
         }
+        self.set_mark();
     }
 
-    // Inside a comment
-    // '-' '-' => InComment2
-    fn in_comment1(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        if c == '-' {
-            self.level += 1;
+    // Record an error that can be recovered from in place in lenient mode,
+    // otherwise return it
+    fn report(&mut self, kind: ParserErrorKind) -> Result<(), ParserError> {
+        let err = self.make_error(kind);
+        if self.config.lenient {
+            self.diagnostics.push(err);
+            Ok(())
         } else {
-            self.level = 0;
+            Err(err)
         }
+    }
 
-        if self.level == 2 {
-            self.level = 0;
-            self.st = State::InComment2;
+    // An error at the current position, whose column is that of the next byte to parse
+    fn make_error(&self, kind: ParserErrorKind) -> ParserError {
+        let position = self.current_position();
+        ParserError {
+            line: position.line,
+            col: position.col,
+            offset: position.offset,
+            kind,
         }
+    }
 
-        self.buf.push(c);
+    fn error<'x>(&self, kind: ParserErrorKind) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        Err(self.make_error(kind))
+    }
 
-        Ok(None)
+    // The construct currently being parsed, if any
+    fn construct(&self) -> Option<Construct> {
+        match self.st {
+            State::OutsideTag => None,
+            State::TagOpened
+            | State::InTagName
+            | State::InCloseTagName
+            | State::InTag
+            | State::InAttrName
+            | State::ExpectDelimiter
+            | State::ExpectClose
+            | State::ExpectSpaceOrClose
+            | State::InExclamationMark => Some(Construct::Tag),
+            State::InAttrValue => Some(Construct::AttributeValue),
+            State::InCommentOpening | State::InComment1 | State::InComment2 => {
+                Some(Construct::Comment)
+            }
+            State::InCDATAOpening | State::InCDATA => Some(Construct::CDATA),
+            State::InProcessingInstructions => Some(Construct::ProcessingInstruction),
+            State::InDoctype
+            | State::InDoctypeDecl
+            | State::InDoctypeLiteral
+            | State::InDoctypeSubset
+            | State::InDoctypeSubsetLiteral
+            | State::InDoctypeSubsetMarkup
+            | State::InDoctypeSubsetComment
+            | State::InDoctypeSubsetPI
+            | State::InDoctypeEnd => Some(Construct::Doctype),
+            State::SkipMarkup => None,
+        }
+    }
+
+    // Produce the buffered character data, or an error if the document is incomplete,
+    // once all input has been parsed after `finish()`
+    fn end_of_input<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let construct = self.construct();
+        if construct.is_none() && self.mark < self.pos {
+            let text = self.mark..self.pos;
+            self.set_mark();
+            return self.characters(data, text);
+        }
+
+        if construct.is_none()
+            && self.open_ends.is_empty()
+            && (self.seen_root || self.follows_document || !self.config.strict)
+        {
+            return Ok(None);
+        }
+        let open_elements = self
+            .open_elements()
+            .into_iter()
+            .map(str::to_owned)
+            .collect();
+        self.error(ParserErrorKind::UnexpectedEof {
+            construct,
+            open_elements,
+        })
+    }
+
+    fn parse_input<'x>(&mut self, data: &'x str) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        match self.st {
+            State::OutsideTag => self.outside_tag(data),
+            State::TagOpened => self.tag_opened(data),
+            State::InProcessingInstructions => self.in_processing_instructions(data),
+            State::InTagName => self.in_tag_name(data),
+            State::InCloseTagName => self.in_close_tag_name(data),
+            State::InTag => self.in_tag(data),
+            State::InAttrName => self.in_attr_name(data),
+            State::InAttrValue => self.in_attr_value(data),
+            State::ExpectDelimiter => self.expect_delimiter(data),
+            State::ExpectClose => self.expect_close(data),
+            State::ExpectSpaceOrClose => self.expect_space_or_close(data),
+            State::InExclamationMark => self.in_exclamation_mark(data),
+            State::InCDATAOpening => self.in_cdata_opening(data),
+            State::InCDATA => self.in_cdata(data),
+            State::InCommentOpening => self.in_comment_opening(data),
+            State::InComment1 => self.in_comment1(data),
+            State::InComment2 => self.in_comment2(data),
+            State::InDoctype => self.in_doctype(data),
+            State::InDoctypeDecl => self.in_doctype_decl(data),
+            State::InDoctypeLiteral => self.in_doctype_literal(data),
+            State::InDoctypeSubset => self.in_doctype_subset(data),
+            State::InDoctypeSubsetLiteral => self.in_doctype_subset_literal(data),
+            State::InDoctypeSubsetMarkup => self.in_doctype_subset_markup(data),
+            State::InDoctypeSubsetComment => self.in_doctype_subset_comment(data),
+            State::InDoctypeSubsetPI => self.in_doctype_subset_pi(data),
+            State::InDoctypeEnd => self.in_doctype_end(data),
+            State::SkipMarkup => self.skip_markup(data),
+        }
+    }
+
+    // Outside any tag, or other construct
+    // '<' => TagOpened, producing BorrowedEvent::Characters
+    fn outside_tag<'x>(&mut self, data: &'x str) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let len = match self.rest(data).find('<') {
+            Some(len) => len,
+            None => {
+                let len = self.rest(data).len();
+                self.advance(data, len);
+                self.check_size(self.pos - self.mark, self.config.max_text_size, || {
+                    ParserErrorKind::TextTooLarge
+                })?;
+                return Ok(None);
+            }
+        };
+        self.advance(data, len);
+        self.check_size(self.pos - self.mark, self.config.max_text_size, || {
+            ParserErrorKind::TextTooLarge
+        })?;
+        let text = self.mark..self.pos;
+        self.set_mark();
+        self.advance(data, 1);
+        self.st = State::TagOpened;
+
+        if text.start == text.end {
+            return Ok(None);
+        }
+        self.characters(data, text)
+    }
+
+    // Produce BorrowedEvent::Characters for the text in the given range
+    fn characters<'x>(
+        &mut self,
+        data: &'x str,
+        text: Range<usize>,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let range = text;
+        let text = self.slice(data, range.clone());
+        let whitespace = text.bytes().all(is_whitespace);
+        if self.config.strict && self.open_ends.is_empty() && !whitespace {
+            self.report(ParserErrorKind::TextOutsideRoot)?;
+        }
+        if whitespace && self.follows_document && range.start == self.doc_start {
+            // Whitespace separating documents may precede the XML declaration
+            self.doc_start = range.end;
+        }
+        let text = self.unescape(text, false)?;
+        Ok(Some(BorrowedEvent::Characters(text)))
     }
 
-    // Closing a comment
-    // '>' => OutsideTag, producing Comment
-    fn in_comment2(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        if c != '>' {
-            self.error(ParserErrorKind::InvalidCommentContent)
-        } else {
-            self.st = State::OutsideTag;
-            let len = self.buf.len();
-            self.buf.truncate(len - 2);
-            let buf = self.take_buf();
-            Ok(Some(Event::Comment(buf)))
-        }
+    // Character following a '<', starting a tag or other construct
+    // '?' => InProcessingInstructions
+    // '!' => InExclamationMark
+    // '/' => InCloseTagName
+    //  _  => InTagName
+    fn tag_opened<'x>(&mut self, data: &'x str) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let c = self.next_char(data);
+        self.start = self.pos;
+        self.st = match c {
+            '?' => State::InProcessingInstructions,
+            '!' => State::InExclamationMark,
+            '/' => State::InCloseTagName,
+            _ => {
+                self.start -= c.len_utf8();
+                State::InTagName
+            }
+        };
+        Ok(None)
+    }
+
+    // Inside a processing instruction
+    // '?' '>' => OutsideTag, producing PI
+    fn in_processing_instructions<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        if self.level == 0 {
+            if self.scan_until(data, |b| b == b'?').is_some() {
+                self.level = 1;
+            }
+            return Ok(None);
+        }
+
+        match self.next_char(data) {
+            '>' => {
+                self.level = 0;
+                let content = self.slice(data, self.start..self.pos - 2);
+                let target_len = content
+                    .bytes()
+                    .position(is_whitespace)
+                    .unwrap_or(content.len());
+                let (target, rest) = content.split_at(target_len);
+                if target == "xml" {
+                    return self.xml_decl(rest);
+                } else if target.eq_ignore_ascii_case("xml") {
+                    return self.error(ParserErrorKind::ReservedPITarget);
+                } else if !is_name(target) {
+                    return self.error(ParserErrorKind::InvalidPITarget);
+                }
+                self.leave_markup();
+                return Ok(Some(BorrowedEvent::PI(BorrowedProcessingInstruction {
+                    target: Cow::Borrowed(target),
+                    data: self.normalize(trim_whitespace_start(rest), false),
+                })));
+            }
+            '?' => (),
+            _ => self.level = 0,
+        }
+        Ok(None)
+    }
+
+    // Produce BorrowedEvent::XmlDecl from the content following the "xml" target
+    fn xml_decl<'x>(&mut self, decl: &'x str) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        if self.mark != self.doc_start {
+            return self.error(ParserErrorKind::MisplacedXmlDecl);
+        }
+        let decl = match parse_xml_decl(decl) {
+            Some(decl) => decl,
+            None => return self.error(ParserErrorKind::InvalidXmlDecl),
+        };
+        if decl.version == "1.1" {
+            // Check the remaining input again, following XML 1.1 rules
+            self.xml11 = true;
+            self.entities.xml11 = true;
+            self.end = self.pos;
+            self.char_error = false;
+        }
+        self.leave_markup();
+        Ok(Some(BorrowedEvent::XmlDecl(decl)))
+    }
+
+    // Inside a tag name (opening tag)
+    // '/' => ExpectClose, producing BorrowedEvent::ElementStart
+    // '>' => OutsideTag, producing BorrowedEvent::ElementStart
+    // ' ' or '\t' or '\r' or '\n' => InTag
+    fn in_tag_name<'x>(&mut self, data: &'x str) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let c = match self.scan_limited(
+            data,
+            |b| b == b'/' || b == b'>' || is_whitespace(b),
+            self.start,
+            self.config.max_name_length,
+            || ParserErrorKind::NameTooLong,
+        )? {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        self.check_name(data, self.start..self.pos - 1)?;
+        self.push_ns_scope();
+        self.name = Some(self.start..self.pos - 1);
+        match c {
+            b'/' | b'>' => return self.start_tag(data, c == b'/'),
+            _ => self.st = State::InTag,
+        }
+        Ok(None)
+    }
+
+    // Inside a tag name (closing tag)
+    // '>' => OutsideTag, producing ElementEnd
+    // ' ' or '\t' or '\r' or '\n' => ExpectSpaceOrClose
+    fn in_close_tag_name<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let c = match self.scan_limited(
+            data,
+            |b| b == b'>' || is_whitespace(b),
+            self.start,
+            self.config.max_name_length,
+            || ParserErrorKind::NameTooLong,
+        )? {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        let name = self.start..self.pos - 1;
+        self.check_name(data, name.clone())?;
+        if c != b'>' {
+            self.name = Some(name);
+            self.st = State::ExpectSpaceOrClose;
+            return Ok(None);
+        }
+        self.close_tag(data, name)
+    }
+
+    // Produce ElementEnd after the closing '>' of an end tag has been parsed
+    fn close_tag<'x>(
+        &mut self,
+        data: &'x str,
+        name: Range<usize>,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let event = self.end_tag(data, name)?;
+        self.leave_markup();
+        Ok(event)
+    }
+
+    // Inside a tag, parsing attributes
+    // '/' => ExpectClose, producing StartTag
+    // '>' => OutsideTag, producing StartTag
+    // ' ' or '\t' or '\r' or '\n' => InAttrName
+    fn in_tag<'x>(&mut self, data: &'x str) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let c = self.next_char(data);
+        match c {
+            '/' | '>' => return self.start_tag(data, c == '/'),
+            ' ' | '\t' | '\r' | '\n' => (),
+            _ => {
+                if self.attributes.len() >= self.config.max_attributes {
+                    return self.error(ParserErrorKind::TooManyAttributes);
+                }
+                self.start = self.pos - c.len_utf8();
+                self.st = State::InAttrName;
+            }
+        }
+        Ok(None)
+    }
+
+    // Produce the start tag from the element name and attributes parsed so far
+    // empty => ExpectClose
+    // otherwise => OutsideTag
+    fn start_tag<'x>(
+        &mut self,
+        data: &'x str,
+        empty: bool,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let attributes = mem::take(&mut self.attributes);
+        let name = self
+            .name
+            .take()
+            .expect("Internal error: No element name set");
+        let qname = self.slice(data, name.clone());
+        if self.open_ends.len() >= self.config.max_depth {
+            return self.error(ParserErrorKind::NestingTooDeep);
+        }
+        if self.open_ends.is_empty() {
+            if self.config.strict && self.seen_root {
+                self.report(ParserErrorKind::MultipleRootElements {
+                    found: qname.to_owned(),
+                })?;
+            }
+            self.seen_root = true;
+        }
+        let (prefix, local) = self.split_name(qname);
+        let ns = self.element_namespace(data, prefix)?;
+
+        let mut attributes_map = AttrMap::new();
+        let mut namespaces = Vec::new();
+
+        // At this point attribute namespaces are really just prefixes,
+        // map them to the actual namespace
+        for (attr, value, unescaped) in attributes {
+            let (attr_prefix, attr_local) = self.split_name(self.slice(data, attr));
+            let binding = match (attr_prefix, attr_local) {
+                (None, "xmlns") if self.config.namespace_aware => Some(None),
+                (Some("xmlns"), prefix) => Some(Some(prefix)),
+                _ => None,
+            };
+            let attr_ns = match attr_prefix {
+                None => None,
+                Some(attr_prefix) => match self.resolve_prefix(data, Some(attr_prefix)) {
+                    None => {
+                        self.report(ParserErrorKind::UnboundNsPrefixInAttributeName)?;
+                        continue;
+                    }
+                    ns => ns,
+                },
+            };
+            let value = match unescaped {
+                Some(unescaped) => Cow::Owned(unescaped),
+                None => Cow::Borrowed(self.slice(data, value)),
+            };
+            if let Some(prefix) = binding {
+                if namespaces
+                    .iter()
+                    .any(|(p, _)| *p == prefix.map(Cow::Borrowed))
+                {
+                    self.report(ParserErrorKind::DuplicateAttribute)?;
+                    continue;
+                }
+                namespaces.push((prefix.map(Cow::Borrowed), value.clone()));
+                if self.config.strip_namespace_declarations {
+                    continue;
+                }
+            }
+            let key = (Cow::Borrowed(attr_local), attr_ns);
+            if attributes_map.contains_key(&key) {
+                self.report(ParserErrorKind::DuplicateAttribute)?;
+                continue;
+            }
+            attributes_map.insert(key, value);
+        }
+
+        self.push_element(qname);
+        if empty {
+            self.name = Some(name);
+            self.st = State::ExpectClose;
+        } else {
+            self.leave_markup();
+        }
+
+        Ok(Some(BorrowedEvent::ElementStart(BorrowedStartTag {
+            name: Cow::Borrowed(local),
+            ns,
+            prefix: prefix.map(Cow::Borrowed),
+            attributes: attributes_map,
+            namespaces,
+        })))
+    }
+
+    // Produce the end tag for the element name in the given range,
+    // closing the innermost element if it matches.
+    // In strict mode an end tag not matching it is an error, in lenient mode it produces no event.
+    fn end_tag<'x>(
+        &mut self,
+        data: &'x str,
+        name: Range<usize>,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let qname = self.slice(data, name);
+        if self.config.strict || self.config.lenient {
+            let kind = match self.current_element() {
+                Some(open) if open == qname => None,
+                Some(open) => Some(ParserErrorKind::MismatchedEndTag {
+                    expected: open.to_owned(),
+                    found: qname.to_owned(),
+                }),
+                None => Some(ParserErrorKind::UnexpectedEndTag {
+                    found: qname.to_owned(),
+                }),
+            };
+            if let Some(kind) = kind {
+                self.report(kind)?;
+                // Close the elements up to the matching one instead, if any
+                if let Some(depth) = self.open_elements().iter().rev().position(|&n| n == qname) {
+                    self.pending_closes = depth + 1;
+                }
+                return Ok(None);
+            }
+        }
+        let (prefix, local) = self.split_name(qname);
+        let ns = self.element_namespace(data, prefix)?;
+        // Otherwise the end tag is reported as written,
+        // only closing the innermost element if it matches
+        if self.current_element() == Some(qname) {
+            self.pop_element();
+            // Keep the element's bindings in scope until the next event
+            self.element_closed();
+        }
+        Ok(Some(BorrowedEvent::ElementEnd(BorrowedEndTag {
+            name: Cow::Borrowed(local),
+            ns,
+            prefix: prefix.map(Cow::Borrowed),
+        })))
+    }
+
+    // Inside an attribute name
+    // '=' => ExpectDelimiter
+    // '>' or '/' or '<' => error, as the attribute has no value
+    fn in_attr_name<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        if self.level == 0 {
+            let c = match self.scan_limited(
+                data,
+                |b| b == b'=' || b == b'>' || b == b'/' || b == b'<' || is_whitespace(b),
+                self.start,
+                self.config.max_name_length,
+                || ParserErrorKind::NameTooLong,
+            )? {
+                Some(c) => c,
+                None => return Ok(None),
+            };
+            if let b'>' | b'/' | b'<' = c {
+                return self.error(ParserErrorKind::MalformedXml);
+            }
+            self.check_name(data, self.start..self.pos - 1)?;
+            self.attr = Some(self.start..self.pos - 1);
+            if c == b'=' {
+                self.st = State::ExpectDelimiter;
+            } else {
+                self.level = 1;
+            }
+            return Ok(None);
+        }
+
+        match self.next_char(data) {
+            '=' => {
+                self.level = 0;
+                self.st = State::ExpectDelimiter;
+            }
+            ' ' | '\t' | '\r' | '\n' => (),
+            '>' | '/' | '<' => return self.error(ParserErrorKind::MalformedXml),
+            _ => return self.error(ParserErrorKind::SpaceInAttributeName),
+        }
+        Ok(None)
+    }
+
+    // Inside an attribute value
+    // delimiter => InTag, adds attribute
+    fn in_attr_value<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let delim = self
+            .delim
+            .expect("Internal error: In attribute value, but no delimiter set")
+            as u8;
+        let found = self.scan_limited(
+            data,
+            |b| b == delim,
+            self.start,
+            self.config.max_attribute_value_size,
+            || ParserErrorKind::AttributeValueTooLarge,
+        )?;
+        if found.is_none() {
+            return Ok(None);
+        }
+
+        self.delim = None;
+        self.st = State::InTag;
+        let attr = self
+            .attr
+            .take()
+            .expect("Internal error: In attribute value, but no attribute name set");
+        let value = self.start..self.pos - 1;
+        let unescaped = self.unescape(self.slice(data, value.clone()), true)?;
+
+        let binding = match self.split_name(self.slice(data, attr.clone())) {
+            (None, "xmlns") if self.config.namespace_aware => Some(String::new()),
+            (Some("xmlns"), name) => Some(name.to_owned()),
+            _ => None,
+        };
+        if let Some(prefix) = binding {
+            // Keep the range of the value, so it can be borrowed while the input is available
+            let range = match unescaped {
+                Cow::Borrowed(_) => Some(value.clone()),
+                Cow::Owned(_) => None,
+            };
+            self.namespaces
+                .push((prefix, unescaped.clone().into_owned(), range));
+        }
+
+        // Only keep a copy if normalizing or unescaping changed the value
+        let unescaped = match unescaped {
+            Cow::Owned(unescaped) => Some(unescaped),
+            Cow::Borrowed(_) => None,
+        };
+        self.attributes.push((attr, value, unescaped));
+        Ok(None)
+    }
+
+    // Looking for an attribute value delimiter
+    // '"' or '\'' => InAttrValue, sets delimiter
+    fn expect_delimiter<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        match self.next_char(data) {
+            c @ '"' | c @ '\'' => {
+                self.delim = Some(c);
+                self.start = self.pos;
+                self.st = State::InAttrValue;
+            }
+            ' ' | '\t' | '\r' | '\n' => (),
+            _ => return self.error(ParserErrorKind::UndelimitedAttribute),
+        }
+        Ok(None)
+    }
+
+    // Expect closing '>' of an empty-element tag (no whitespace allowed)
+    // '>' => OutsideTag
+    fn expect_close<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        match self.next_char(data) {
+            '>' => {
+                let name = self
+                    .name
+                    .take()
+                    .expect("Internal error: No element name set");
+                self.close_tag(data, name)
+            }
+            _ => self.error(ParserErrorKind::ExpectedTagClose),
+        }
+    }
+
+    // Expect closing '>' of an end tag
+    // '>' => OutsideTag, producing ElementEnd
+    fn expect_space_or_close<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        match self.next_char(data) {
+            ' ' | '\t' | '\r' | '\n' => Ok(None),
+            '>' => {
+                let name = self
+                    .name
+                    .take()
+                    .expect("Internal error: No element name set");
+                self.close_tag(data, name)
+            }
+            _ => self.error(ParserErrorKind::ExpectedLwsOrTagClose),
+        }
+    }
+
+    // After an '!' trying to determine the type of the following construct
+    // '-' => InCommentOpening
+    // '[' => InCDATAOpening
+    // 'D' => InDoctype
+    fn in_exclamation_mark<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        self.st = match self.next_char(data) {
+            '-' => State::InCommentOpening,
+            '[' => State::InCDATAOpening,
+            'D' => State::InDoctype,
+            _ => return self.error(ParserErrorKind::MalformedXml),
+        };
+        Ok(None)
+    }
+
+    // Opening sequence of BorrowedEvent::CDATA
+    // 'C' 'D' 'A' 'T' 'A' '[' => InCDATA
+    fn in_cdata_opening<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        static CDATA_PATTERN: [char; 6] = ['C', 'D', 'A', 'T', 'A', '['];
+        if self.next_char(data) == CDATA_PATTERN[self.level as usize] {
+            self.level += 1;
+        } else {
+            return self.error(ParserErrorKind::InvalidCdataStart);
+        }
+
+        if self.level == 6 {
+            self.level = 0;
+            self.start = self.pos;
+            self.st = State::InCDATA;
+        }
+        Ok(None)
+    }
+
+    // Inside CDATA
+    // ']' ']' '>' => OutsideTag, producing BorrowedEvent::CDATA
+    fn in_cdata<'x>(&mut self, data: &'x str) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        if self.level == 0 {
+            let found = self.scan_limited(
+                data,
+                |b| b == b']',
+                self.start,
+                self.config.max_text_size,
+                || ParserErrorKind::TextTooLarge,
+            )?;
+            if found.is_some() {
+                self.level = 1;
+            }
+            return Ok(None);
+        }
+
+        match self.next_char(data) {
+            ']' => self.level = 2,
+            '>' if self.level == 2 => {
+                self.level = 0;
+                if self.config.strict && self.open_ends.is_empty() {
+                    self.report(ParserErrorKind::TextOutsideRoot)?;
+                }
+                let content = self.start..self.pos - 3;
+                self.leave_markup();
+                return Ok(Some(BorrowedEvent::CDATA(
+                    self.normalize(self.slice(data, content), false),
+                )));
+            }
+            _ => self.level = 0,
+        }
+        Ok(None)
+    }
+
+    // Opening sequence of a comment
+    // '-' => InComment1
+    fn in_comment_opening<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        if self.next_char(data) == '-' {
+            self.st = State::InComment1;
+            self.level = 0;
+            self.start = self.pos;
+            Ok(None)
+        } else {
+            self.error(ParserErrorKind::InvalidCommentStart)
+        }
+    }
+
+    // Inside a comment
+    // '-' '-' => InComment2
+    fn in_comment1<'x>(&mut self, data: &'x str) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        if self.level == 0 {
+            if self.scan_until(data, |b| b == b'-').is_some() {
+                self.level = 1;
+            }
+            return Ok(None);
+        }
+
+        if self.next_char(data) == '-' {
+            self.st = State::InComment2;
+        }
+        self.level = 0;
+        Ok(None)
+    }
+
+    // Closing a comment
+    // '>' => OutsideTag, producing Comment
+    fn in_comment2<'x>(&mut self, data: &'x str) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        if self.next_char(data) != '>' {
+            self.error(ParserErrorKind::InvalidCommentContent)
+        } else {
+            let content = self.start..self.pos - 3;
+            self.leave_markup();
+            Ok(Some(BorrowedEvent::Comment(
+                self.normalize(self.slice(data, content), false),
+            )))
+        }
+    }
+
+    // Inside a doctype
+    // 'O' 'C' 'T' 'Y' 'P' 'E' whitespace => InDoctypeDecl
+    fn in_doctype<'x>(&mut self, data: &'x str) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        static DOCTYPE_PATTERN: [char; 6] = ['O', 'C', 'T', 'Y', 'P', 'E'];
+        if self.level < 6 {
+            if self.next_char(data) == DOCTYPE_PATTERN[self.level as usize] {
+                self.level += 1;
+            } else {
+                return self.error(ParserErrorKind::InvalidDoctype);
+            }
+        } else {
+            match self.next_char(data) {
+                ' ' | '\t' | '\r' | '\n' => (),
+                _ => return self.error(ParserErrorKind::InvalidDoctype),
+            }
+            self.level = 0;
+            self.start = self.pos;
+            self.st = State::InDoctypeDecl;
+        }
+        Ok(None)
+    }
+
+    // Inside a doctype declaration, outside of literals and the internal subset
+    // '"' or '\'' => InDoctypeLiteral, sets delimiter
+    // '[' => InDoctypeSubset
+    // '>' => OutsideTag, producing BorrowedEvent::Doctype
+    fn in_doctype_decl<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let c = match self.scan_until(data, |b| b == b'"' || b == b'\'' || b == b'[' || b == b'>') {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        match c {
+            b'"' | b'\'' => {
+                self.delim = Some(char::from(c));
+                self.st = State::InDoctypeLiteral;
+            }
+            b'[' => self.st = State::InDoctypeSubset,
+            _ => return self.doctype_end(data),
+        }
+        Ok(None)
+    }
+
+    // Produce BorrowedEvent::Doctype after the closing '>' has been parsed
+    fn doctype_end<'x>(&mut self, data: &'x str) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let decl = self.slice(data, self.start..self.pos - 1);
+        let mut doctype = match parse_doctype(decl) {
+            Some(doctype) => doctype,
+            None => return self.error(ParserErrorKind::InvalidDoctype),
+        };
+        if let Some(Cow::Borrowed(subset)) = doctype.internal_subset {
+            let subset = self.normalize(subset, false);
+            if let Err(kind) = self.entities.declare(&subset) {
+                return self.error(kind);
+            }
+            doctype.internal_subset = Some(subset);
+        }
+        self.leave_markup();
+        Ok(Some(BorrowedEvent::Doctype(doctype)))
+    }
+
+    // Inside a quoted literal of a doctype declaration
+    // delimiter => InDoctypeDecl
+    fn in_doctype_literal<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let delim = self
+            .delim
+            .expect("Internal error: In doctype literal, but no delimiter set")
+            as u8;
+        if self.scan_until(data, |b| b == delim).is_some() {
+            self.delim = None;
+            self.st = State::InDoctypeDecl;
+        }
+        Ok(None)
+    }
+
+    // Inside the internal subset of a doctype declaration
+    // '"' or '\'' => InDoctypeSubsetLiteral, sets delimiter
+    // '<' => InDoctypeSubsetMarkup
+    // ']' => InDoctypeEnd
+    fn in_doctype_subset<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let c = match self.scan_until(data, |b| b == b'"' || b == b'\'' || b == b'<' || b == b']') {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        match c {
+            b'"' | b'\'' => {
+                self.delim = Some(char::from(c));
+                self.st = State::InDoctypeSubsetLiteral;
+            }
+            b'<' => {
+                self.level = 0;
+                self.st = State::InDoctypeSubsetMarkup;
+            }
+            _ => self.st = State::InDoctypeEnd,
+        }
+        Ok(None)
+    }
+
+    // Inside a quoted literal of the internal subset
+    // delimiter => InDoctypeSubset
+    fn in_doctype_subset_literal<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let delim = self
+            .delim
+            .expect("Internal error: In doctype literal, but no delimiter set")
+            as u8;
+        if self.scan_until(data, |b| b == delim).is_some() {
+            self.delim = None;
+            self.st = State::InDoctypeSubset;
+        }
+        Ok(None)
+    }
+
+    // Start of markup inside the internal subset, after '<'
+    // '?' => InDoctypeSubsetPI
+    // '!' '-' '-' => InDoctypeSubsetComment
+    // anything else => InDoctypeSubset, without consuming it
+    fn in_doctype_subset_markup<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        let c = self.rest(data).as_bytes()[0];
+        match (self.level, c) {
+            (0, b'?') => {
+                self.next_char(data);
+                self.st = State::InDoctypeSubsetPI;
+            }
+            (0, b'!') | (1, b'-') => {
+                self.next_char(data);
+                self.level += 1;
+                return Ok(None);
+            }
+            (2, b'-') => {
+                self.next_char(data);
+                self.st = State::InDoctypeSubsetComment;
+            }
+            (2, _) => return self.error(ParserErrorKind::InvalidCommentStart),
+            _ => self.st = State::InDoctypeSubset,
+        }
+        self.level = 0;
+        Ok(None)
+    }
+
+    // Inside a comment in the internal subset
+    // '-' '-' '>' => InDoctypeSubset
+    fn in_doctype_subset_comment<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        if self.level == 0 {
+            if self.scan_until(data, |b| b == b'-').is_some() {
+                self.level = 1;
+            }
+            return Ok(None);
+        }
+
+        match (self.level, self.next_char(data)) {
+            (1, '-') => self.level = 2,
+            (1, _) => self.level = 0,
+            (_, '>') => {
+                self.level = 0;
+                self.st = State::InDoctypeSubset;
+            }
+            _ => return self.error(ParserErrorKind::InvalidCommentContent),
+        }
+        Ok(None)
+    }
+
+    // Inside a processing instruction in the internal subset
+    // '?' '>' => InDoctypeSubset
+    fn in_doctype_subset_pi<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        if self.level == 0 {
+            if self.scan_until(data, |b| b == b'?').is_some() {
+                self.level = 1;
+            }
+            return Ok(None);
+        }
+
+        match self.next_char(data) {
+            '>' => {
+                self.level = 0;
+                self.st = State::InDoctypeSubset;
+            }
+            '?' => (),
+            _ => self.level = 0,
+        }
+        Ok(None)
+    }
+
+    // Skipping the rest of malformed markup in lenient mode
+    // '>' => OutsideTag
+    fn skip_markup<'x>(&mut self, data: &'x str) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        if self.scan_until(data, |b| b == b'>').is_some() {
+            self.st = State::OutsideTag;
+        }
+        // Skipped input is not part of any text
+        self.set_mark();
+        Ok(None)
+    }
+
+    // After the internal subset of a doctype declaration
+    // whitespace => InDoctypeEnd
+    // '>' => OutsideTag, producing BorrowedEvent::Doctype
+    fn in_doctype_end<'x>(
+        &mut self,
+        data: &'x str,
+    ) -> Result<Option<BorrowedEvent<'x>>, ParserError> {
+        match self.next_char(data) {
+            ' ' | '\t' | '\r' | '\n' => Ok(None),
+            '>' => self.doctype_end(data),
+            _ => self.error(ParserErrorKind::InvalidDoctype),
+        }
+    }
+}
+
+#[cfg(test)]
+mod parser_tests {
+    use super::{
+        BorrowedParser, Construct, Parser, ParserConfig, ParserErrorKind, ParserState, Position,
+        Pull, Span,
+    };
+    use crate::{
+        AttrMap, BorrowedEvent, Doctype, EndTag, Event, ParserError, ProcessingInstruction,
+        StartTag, XmlDecl,
+    };
+    use std::borrow::Cow;
+
+    #[test]
+    fn test_start_tag() {
+        let mut p = Parser::new();
+        let mut i = 0u8;
+        p.feed_str("<a>");
+        for event in p {
+            i += 1;
+            assert_eq!(
+                event,
+                Ok(Event::ElementStart(StartTag {
+                    name: "a".to_owned(),
+                    ns: None,
+                    prefix: None,
+                    attributes: AttrMap::new(),
+                    namespaces: Vec::new(),
+                })),
+            );
+        }
+        assert_eq!(i, 1u8);
+    }
+
+    #[test]
+    fn test_end_tag() {
+        let mut p = Parser::new();
+        let mut i = 0u8;
+        p.feed_str("</a>");
+        for event in p {
+            i += 1;
+            assert_eq!(
+                event,
+                Ok(Event::ElementEnd(EndTag {
+                    name: "a".to_owned(),
+                    ns: None,
+                    prefix: None
+                })),
+            );
+        }
+        assert_eq!(i, 1u8);
+    }
+
+    #[test]
+    fn test_self_closing_with_space() {
+        let mut p = Parser::new();
+        p.feed_str("<register />");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(
+            v,
+            vec![
+                Ok(Event::ElementStart(StartTag {
+                    name: "register".to_owned(),
+                    ns: None,
+                    prefix: None,
+                    attributes: AttrMap::new(),
+                    namespaces: Vec::new(),
+                })),
+                Ok(Event::ElementEnd(EndTag {
+                    name: "register".to_owned(),
+                    ns: None,
+                    prefix: None,
+                }))
+            ],
+        );
+    }
+
+    #[test]
+    fn test_self_closing_without_space() {
+        let mut p = Parser::new();
+        p.feed_str("<register/>");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(
+            v,
+            vec![
+                Ok(Event::ElementStart(StartTag {
+                    name: "register".to_owned(),
+                    ns: None,
+                    prefix: None,
+                    attributes: AttrMap::new(),
+                    namespaces: Vec::new(),
+                })),
+                Ok(Event::ElementEnd(EndTag {
+                    name: "register".to_owned(),
+                    ns: None,
+                    prefix: None,
+                }))
+            ],
+        );
+    }
+
+    #[test]
+    fn test_self_closing_namespace() {
+        let mut p = Parser::new();
+        p.feed_str("<foo:a xmlns:foo='urn:foo'/>");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        let mut attr: AttrMap<(String, Option<String>), String> = AttrMap::new();
+        attr.insert(
+            (
+                "foo".to_owned(),
+                Some("http://www.w3.org/2000/xmlns/".to_owned()),
+            ),
+            "urn:foo".to_owned(),
+        );
+        assert_eq!(
+            v,
+            vec![
+                Ok(Event::ElementStart(StartTag {
+                    name: "a".to_owned(),
+                    ns: Some("urn:foo".to_owned()),
+                    prefix: Some("foo".to_owned()),
+                    attributes: attr,
+                    namespaces: vec![(Some("foo".to_owned()), "urn:foo".to_owned())],
+                })),
+                Ok(Event::ElementEnd(EndTag {
+                    name: "a".to_owned(),
+                    ns: Some("urn:foo".to_owned()),
+                    prefix: Some("foo".to_owned()),
+                }))
+            ],
+        );
+    }
+
+    #[test]
+    fn test_pi() {
+        let mut p = Parser::new();
+        let mut i = 0u8;
+        p.feed_str("<?xml-stylesheet href='style.css' type='text/css'?>");
+        for event in p {
+            i += 1;
+            assert_eq!(
+                event,
+                Ok(Event::PI(ProcessingInstruction {
+                    target: "xml-stylesheet".to_owned(),
+                    data: "href='style.css' type='text/css'".to_owned(),
+                })),
+            );
+        }
+        assert_eq!(i, 1u8);
+    }
+
+    #[test]
+    fn test_comment() {
+        let mut p = Parser::new();
+        let mut i = 0u8;
+        p.feed_str("<!--Nothing to see-->");
+        for event in p {
+            i += 1;
+            assert_eq!(event, Ok(Event::Comment("Nothing to see".to_owned())));
+        }
+        assert_eq!(i, 1u8);
+    }
+
+    #[test]
+    fn test_cdata() {
+        let mut p = Parser::new();
+        let mut i = 0u8;
+        p.feed_str("<![CDATA[<html><head><title>x</title></head><body/></html>]]>");
+        for event in p {
+            i += 1;
+            assert_eq!(
+                event,
+                Ok(Event::CDATA(
+                    "<html><head><title>x</title></head><body/></html>".to_owned()
+                )),
+            );
+        }
+        assert_eq!(i, 1u8);
+    }
+
+    #[test]
+    fn test_characters() {
+        let mut p = Parser::new();
+        let mut i = 0u8;
+        p.feed_str("<text>Hello World, it&apos;s a nice day</text>");
+        for event in p {
+            i += 1;
+            if i == 2 {
+                assert_eq!(
+                    event,
+                    Ok(Event::Characters("Hello World, it's a nice day".to_owned())),
+                );
+            }
+        }
+        assert_eq!(i, 3u8);
+    }
+
+    #[test]
+    fn test_doctype() {
+        let mut p = Parser::new();
+        p.feed_str("<!DOCTYPE html>");
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(
+            v,
+            vec![Ok(Event::Doctype(Doctype {
+                name: "html".to_owned(),
+                public_id: None,
+                system_id: None,
+                internal_subset: None,
+            }))],
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "ordered_attrs")]
+    fn test_attribute_order() {
+        let input = "<a href='/' title='Home' target='_blank'>";
+        let expected_attributes = [
+            (("href".to_owned(), None), "/".to_owned()),
+            (("title".to_owned(), None), "Home".to_owned()),
+            (("target".to_owned(), None), "_blank".to_owned()),
+        ];
+
+        // Run this 5 times to make it unlikely this test succeeds at random
+        for _ in 0..5 {
+            let mut p = Parser::new();
+            p.feed_str(input);
+            if let Some(Ok(Event::ElementStart(tag))) = p.next() {
+                for (expected, actual) in expected_attributes.iter().zip(tag.attributes) {
+                    assert_eq!(expected, &actual);
+                }
+            } else {
+                panic!("Missing ElementStart event");
+            }
+            assert!(p.next().is_none());
+        }
+    }
+
+    #[test]
+    fn test_feed_bytes_split_char() {
+        let mut p = Parser::new();
+        let input = "<a>\u{e4}\u{20ac}\u{1f600}</a>".as_bytes();
+        for chunk in input.chunks(1) {
+            p.feed_bytes(chunk);
+        }
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(v.len(), 3);
+        assert_eq!(
+            v[1],
+            Ok(Event::Characters("\u{e4}\u{20ac}\u{1f600}".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_feed_bytes_invalid() {
+        let mut p = Parser::new();
+        p.feed_bytes(b"<a>\n ab");
+        p.feed_bytes(b"\xe4\xff</a>");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(
+            v[1],
+            Err(ParserError {
+                line: 2,
+                col: 4,
+                offset: 7,
+                kind: ParserErrorKind::InvalidUtf8,
+            }),
+        );
+    }
+
+    #[test]
+    fn test_feed_bytes_latin1() {
+        let mut p = Parser::new();
+        p.feed_bytes(b"<?xml version='1.0' encoding='ISO-8859-1'?>");
+        p.feed_bytes(b"<a>Gr\xFC\xDFe</a>");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(v[2], Ok(Event::Characters("Gr\u{fc}\u{df}e".to_owned())));
+    }
+
+    #[test]
+    fn test_chunked_input() {
+        let input = include_str!("../data/test01.xml");
+        let mut p = Parser::new();
+        p.feed_str(input);
+        let expected: Vec<Result<Event, ParserError>> = p.collect();
+
+        for &size in &[1, 2, 7, 64] {
+            let mut p = Parser::new();
+            let mut v = Vec::new();
+            for chunk in input.as_bytes().chunks(size) {
+                p.feed_bytes(chunk);
+                v.extend(&mut p);
+            }
+            assert_eq!(v, expected);
+        }
+    }
+
+    #[test]
+    fn test_borrowed_parser() {
+        let input = include_str!("../data/test01.xml");
+        let mut p = Parser::new();
+        p.feed_str(input);
+        p.finish();
+        let expected: Vec<Result<Event, ParserError>> = p.collect();
+
+        let v: Vec<Result<Event, ParserError>> = BorrowedParser::new(input)
+            .map(|event| event.map(BorrowedEvent::into_owned))
+            .collect();
+        assert_eq!(v, expected);
+    }
+
+    #[test]
+    fn test_borrowed_events() {
+        let mut p = BorrowedParser::new("<a b='c' d='&amp;'>x &lt; y</a>");
+        match p.next() {
+            Some(Ok(BorrowedEvent::ElementStart(tag))) => {
+                assert!(match tag.name {
+                    Cow::Borrowed("a") => true,
+                    _ => false,
+                });
+                let b = &tag.attributes[&(Cow::Borrowed("b"), None)];
+                assert!(match *b {
+                    Cow::Borrowed("c") => true,
+                    _ => false,
+                });
+                let d = &tag.attributes[&(Cow::Borrowed("d"), None)];
+                assert!(match *d {
+                    Cow::Owned(ref d) => d == "&",
+                    _ => false,
+                });
+            }
+            e => panic!("Unexpected event {:?}", e),
+        }
+        assert!(match p.next() {
+            Some(Ok(BorrowedEvent::Characters(Cow::Owned(ref text)))) => text == "x < y",
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_doctype_external_id() {
+        let mut p = Parser::new();
+        p.feed_str(
+            "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\"\n\
+             'http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd' [<!ENTITY a 'b'>] >",
+        );
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(
+            v,
+            vec![Ok(Event::Doctype(Doctype {
+                name: "html".to_owned(),
+                public_id: Some("-//W3C//DTD XHTML 1.0 Strict//EN".to_owned()),
+                system_id: Some("http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd".to_owned()),
+                internal_subset: Some("<!ENTITY a 'b'>".to_owned()),
+            }))],
+        );
+
+        let mut p = Parser::new();
+        p.feed_str("<!DOCTYPE greeting SYSTEM \"hello>.dtd\"><greeting/>");
+        match p.next() {
+            Some(Ok(Event::Doctype(doctype))) => {
+                assert_eq!(doctype.system_id, Some("hello>.dtd".to_owned()))
+            }
+            e => panic!("Unexpected event {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_doctype_invalid() {
+        for doc in &[
+            "<!DOCTYPE html SYSTEM>",
+            "<!DOCTYPE a [] b>",
+            "<!DOCTYPE a [] [] >",
+        ] {
+            let mut p = Parser::new();
+            p.feed_str(doc);
+            assert_eq!(
+                p.next().map(|e| e.map_err(|e| e.kind)),
+                Some(Err(ParserErrorKind::InvalidDoctype)),
+            );
+        }
+    }
+
+    #[test]
+    fn test_doctype_internal_subset() {
+        let subset = "\n  <!ENTITY x \"y]>\">\n  <!-- ]> -->\n  <?pi ]>?>\n  \
+                      <!ATTLIST a b CDATA '>'>\n";
+        let doc = format!("<!DOCTYPE a [{}] ><a>&amp;</a>", subset);
+        for chunk_size in 1..doc.len() {
+            let mut p = Parser::new();
+            let mut v = Vec::new();
+            for chunk in doc.as_bytes().chunks(chunk_size) {
+                p.feed_bytes(chunk);
+                v.extend(&mut p);
+            }
+            assert_eq!(
+                v,
+                vec![
+                    Ok(Event::Doctype(Doctype {
+                        name: "a".to_owned(),
+                        public_id: None,
+                        system_id: None,
+                        internal_subset: Some(subset.to_owned()),
+                    })),
+                    Ok(Event::ElementStart(StartTag {
+                        name: "a".to_owned(),
+                        ns: None,
+                        prefix: None,
+                        attributes: AttrMap::new(),
+                        namespaces: Vec::new(),
+                    })),
+                    Ok(Event::Characters("&".to_owned())),
+                    Ok(Event::ElementEnd(EndTag {
+                        name: "a".to_owned(),
+                        ns: None,
+                        prefix: None,
+                    })),
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn test_declared_entities() {
+        let mut p = Parser::new();
+        p.feed_str(
+            "<!DOCTYPE a [<!ENTITY company 'ACME'><!ENTITY name '&company; &amp; Co'>]>\
+             <a b='&name;'>&company;</a>",
+        );
+        let v: Vec<Event> = p.skip(1).map(|e| e.unwrap()).collect();
+        match v[0] {
+            Event::ElementStart(ref tag) => {
+                assert_eq!(tag.attributes[&("b".to_owned(), None)], "ACME & Co")
+            }
+            ref e => panic!("Unexpected event {:?}", e),
+        }
+        assert_eq!(v[1], Event::Characters("ACME".to_owned()));
+    }
+
+    #[test]
+    fn test_entity_limits() {
+        let mut p = Parser::new();
+        p.feed_str(
+            "<!DOCTYPE lolz [\
+             <!ENTITY lol 'lol'>\
+             <!ENTITY lol1 '&lol;&lol;&lol;&lol;&lol;&lol;&lol;&lol;&lol;&lol;'>\
+             <!ENTITY lol2 '&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;'>\
+             <!ENTITY lol3 '&lol2;&lol2;&lol2;&lol2;&lol2;&lol2;&lol2;&lol2;&lol2;&lol2;'>\
+             ]><lolz>&lol3;</lolz>",
+        );
+        p.set_max_entity_expansion(1000);
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(
+            v.last().unwrap().as_ref().map_err(|e| e.kind.clone()),
+            Err(ParserErrorKind::EntityExpansionTooLarge),
+        );
+
+        let mut p = Parser::new();
+        p.feed_str("<!DOCTYPE a [<!ENTITY a '&b;'><!ENTITY b '&a;'>]><a>&a;</a>");
+        p.set_max_entity_depth(4);
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(
+            v.last().unwrap().as_ref().map_err(|e| e.kind.clone()),
+            Err(ParserErrorKind::EntityNestingTooDeep),
+        );
+    }
+
+    #[test]
+    fn test_xml_decl() {
+        let mut p = Parser::new();
+        p.feed_str("<?xml version='1.0' encoding=\"utf-8\" standalone='yes' ?><?xml-stylesheet?>");
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(
+            v,
+            vec![
+                Ok(Event::XmlDecl(XmlDecl {
+                    version: "1.0".to_owned(),
+                    encoding: Some("utf-8".to_owned()),
+                    standalone: Some(true),
+                })),
+                Ok(Event::PI(ProcessingInstruction {
+                    target: "xml-stylesheet".to_owned(),
+                    data: String::new(),
+                })),
+            ],
+        );
+
+        let mut p = Parser::new();
+        p.feed_str("<?xml version = \"1.1\"?>");
+        assert_eq!(
+            p.next(),
+            Some(Ok(Event::XmlDecl(XmlDecl {
+                version: "1.1".to_owned(),
+                encoding: None,
+                standalone: None,
+            }))),
+        );
+    }
+
+    #[test]
+    fn test_xml_decl_invalid() {
+        let cases = [
+            ("<?xml?>", ParserErrorKind::InvalidXmlDecl),
+            ("<?xml encoding='utf-8'?>", ParserErrorKind::InvalidXmlDecl),
+            ("<?xml version='2.0'?>", ParserErrorKind::InvalidXmlDecl),
+            (
+                "<?xml version='1.0' standalone='maybe'?>",
+                ParserErrorKind::InvalidXmlDecl,
+            ),
+            (
+                "<?xml version='1.0' standalone='no' encoding='utf-8'?>",
+                ParserErrorKind::InvalidXmlDecl,
+            ),
+            (" <?xml version='1.0'?>", ParserErrorKind::MisplacedXmlDecl),
+        ];
+        for &(doc, ref kind) in cases.iter() {
+            let mut p = Parser::new();
+            p.feed_str(doc);
+            assert_eq!(
+                p.last().map(|e| e.map_err(|e| e.kind)),
+                Some(Err(kind.clone()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_pi_invalid() {
+        let cases = [
+            ("<a><?XML?></a>", ParserErrorKind::ReservedPITarget),
+            ("<?Xml version='1.0'?>", ParserErrorKind::ReservedPITarget),
+            ("<? target?>", ParserErrorKind::InvalidPITarget),
+        ];
+        for &(doc, ref kind) in cases.iter() {
+            let mut p = Parser::new();
+            p.feed_str(doc);
+            assert_eq!(
+                p.last().map(|e| e.map_err(|e| e.kind)),
+                Some(Err(kind.clone()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_strict() {
+        let cases = [
+            (
+                "<a><b></a>",
+                ParserErrorKind::MismatchedEndTag {
+                    expected: "b".to_owned(),
+                    found: "a".to_owned(),
+                },
+            ),
+            (
+                "<a xmlns:x='urn:x'><x:b></b></a>",
+                ParserErrorKind::MismatchedEndTag {
+                    expected: "x:b".to_owned(),
+                    found: "b".to_owned(),
+                },
+            ),
+            (
+                "<a/></a>",
+                ParserErrorKind::UnexpectedEndTag {
+                    found: "a".to_owned(),
+                },
+            ),
+            (
+                "<a/>\n<b/>",
+                ParserErrorKind::MultipleRootElements {
+                    found: "b".to_owned(),
+                },
+            ),
+            ("<a/>text<!---->", ParserErrorKind::TextOutsideRoot),
+            ("&amp;<a/>", ParserErrorKind::TextOutsideRoot),
+            ("<![CDATA[ ]]><a/>", ParserErrorKind::TextOutsideRoot),
+        ];
+        for &(doc, ref kind) in cases.iter() {
+            let mut p = Parser::new();
+            p.set_strict(true);
+            p.feed_str(doc);
+            assert_eq!(
+                p.last().map(|e| e.map_err(|e| e.kind)),
+                Some(Err(kind.clone()))
+            );
+
+            let mut p = Parser::new();
+            p.feed_str(doc);
+            assert!(p.all(|e| e.is_ok()));
+        }
+
+        let mut p = Parser::new();
+        p.set_strict(true);
+        p.feed_str("<?pi?>\n<!-- c -->\n<a><b/><c>text</c></a>\n<!-- c -->\n");
+        assert!(p.all(|e| e.is_ok()));
+    }
+
+    #[test]
+    fn test_finish() {
+        let mut p = Parser::new();
+        p.feed_str("<a>text");
+        assert!(p.next().unwrap().is_ok());
+        assert_eq!(p.next(), None);
+        p.finish();
+        assert_eq!(p.next(), Some(Ok(Event::Characters("text".to_owned()))));
+        let err = p.next().unwrap().unwrap_err();
+        assert_eq!(
+            err.kind,
+            ParserErrorKind::UnexpectedEof {
+                construct: None,
+                open_elements: vec!["a".to_owned()],
+            }
+        );
+        assert_eq!(
+            format!("{}", err),
+            "Parse error; Line: 1, Column: 8, Reason: \
+             Unexpected end of input, unclosed elements: a",
+        );
+        assert_eq!(p.next(), None);
+
+        let mut p = Parser::new();
+        p.feed_str("<a/>\n");
+        p.finish();
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        assert_eq!(v.len(), 3);
+        assert!(v.iter().all(|e| e.is_ok()));
+    }
+
+    #[test]
+    fn test_finish_unterminated() {
+        let cases = [
+            ("<a><b x='1", Some(Construct::AttributeValue)),
+            ("<a><b", Some(Construct::Tag)),
+            ("<a></a", Some(Construct::Tag)),
+            ("<a><!-- x -", Some(Construct::Comment)),
+            ("<a><![CDATA[ x ]]", Some(Construct::CDATA)),
+            ("<a><?pi", Some(Construct::ProcessingInstruction)),
+            ("<!DOCTYPE a [", Some(Construct::Doctype)),
+        ];
+        for &(doc, construct) in cases.iter() {
+            let mut p = Parser::new();
+            p.feed_str(doc);
+            p.finish();
+            let open_elements = if doc.starts_with("<a>") {
+                vec!["a".to_owned()]
+            } else {
+                vec![]
+            };
+            assert_eq!(
+                p.last().map(|e| e.map_err(|e| e.kind)),
+                Some(Err(ParserErrorKind::UnexpectedEof {
+                    construct,
+                    open_elements,
+                })),
+            );
+        }
+
+        let mut p = Parser::new();
+        p.set_strict(true);
+        p.feed_str("<!-- no root -->");
+        p.finish();
+        assert_eq!(
+            p.last().map(|e| e.map_err(|e| e.kind)),
+            Some(Err(ParserErrorKind::UnexpectedEof {
+                construct: None,
+                open_elements: vec![],
+            })),
+        );
+
+        let mut p = Parser::new();
+        p.feed_bytes(b"<a>\xC3");
+        p.finish();
+        assert_eq!(
+            p.last().map(|e| e.map_err(|e| e.kind)),
+            Some(Err(ParserErrorKind::InvalidUtf8)),
+        );
+    }
+
+    #[test]
+    fn test_pull() {
+        let mut p = Parser::new();
+        assert_eq!(p.pull(), Ok(Pull::NeedMoreInput));
+        assert_eq!(p.state(), ParserState::BetweenDocuments);
+        p.feed_str("<?xml version='1.0'?>\n<a><b x='");
+        assert!(match p.pull() {
+            Ok(Pull::Event(Event::XmlDecl(_))) => true,
+            _ => false,
+        });
+        assert_eq!(p.state(), ParserState::BetweenDocuments);
+        assert!(match p.pull() {
+            Ok(Pull::Event(Event::Characters(_))) => true,
+            _ => false,
+        });
+        assert!(match p.pull() {
+            Ok(Pull::Event(Event::ElementStart(_))) => true,
+            _ => false,
+        });
+        assert_eq!(p.state(), ParserState::InText);
+        assert_eq!(p.pull(), Ok(Pull::NeedMoreInput));
+        assert_eq!(p.state(), ParserState::InMarkup(Construct::AttributeValue));
+        p.feed_str("1'/>text</a>");
+        assert_eq!(p.by_ref().count(), 4);
+        assert_eq!(p.state(), ParserState::BetweenDocuments);
+        assert_eq!(p.pull(), Ok(Pull::EndOfDocument));
+
+        p.feed_str("</b>");
+        p.set_strict(true);
+        assert!(p.pull().is_err());
+        assert_eq!(p.state(), ParserState::Failed);
+        assert_eq!(p.pull(), Ok(Pull::EndOfDocument));
+
+        let mut p = Parser::new();
+        p.feed_str("text");
+        p.finish();
+        assert!(match p.pull() {
+            Ok(Pull::Event(Event::Characters(_))) => true,
+            _ => false,
+        });
+        assert_eq!(p.pull(), Ok(Pull::EndOfDocument));
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        for doc in &[
+            "<1a/>",
+            "<a b@c='x'/>",
+            "<a -b ='x'/>",
+            "<a></a$>",
+            "< a/>",
+            "<a\u{D7}/>",
+        ] {
+            let mut p = Parser::new();
+            p.feed_str(doc);
+            assert_eq!(
+                p.last().map(|e| e.map_err(|e| e.kind)),
+                Some(Err(ParserErrorKind::InvalidName)),
+            );
+        }
+
+        let mut p = Parser::new();
+        p.feed_str("<\u{e4}-b.c_1 \u{3b1}\u{b7}='x'></\u{e4}-b.c_1>");
+        assert!(p.all(|e| e.is_ok()));
+    }
+
+    #[test]
+    fn test_invalid_chars() {
+        let cases = [
+            ("<a>\u{1}</a>", 1, 4, 3),
+            ("<a>&#0;</a>", 1, 9, 8),
+            ("<a>&#x1;</a>", 1, 10, 9),
+            ("<a b='\u{FFFE}'/>", 1, 7, 6),
+            ("<?xml version='1.1'?><a>\u{80}</a>", 1, 25, 24),
+            ("<?xml version='1.1'?><a>\u{0}</a>", 1, 25, 24),
+        ];
+        for &(doc, line, col, offset) in cases.iter() {
+            let mut p = Parser::new();
+            p.feed_str(doc);
+            assert_eq!(
+                p.last(),
+                Some(Err(ParserError {
+                    line,
+                    col,
+                    offset,
+                    kind: ParserErrorKind::InvalidChar,
+                })),
+            );
+        }
+
+        let mut p = Parser::new();
+        p.feed_str("<a>\t\u{80}\u{85}\u{FFFD}&#x9;&#x10FFFF;</a>");
+        assert!(p.all(|e| e.is_ok()));
+
+        let mut p = Parser::new();
+        p.feed_str("<?xml version='1.1'?><a>&#x1;\u{85}&#x80;</a>");
+        assert!(p.all(|e| e.is_ok()));
+    }
+
+    #[test]
+    fn test_normalization() {
+        let doc = "<a b='x\r\ny\tz&#10;'>1\r\n2\r3<![CDATA[\r\n]]><!--\r--><?pi a\r\nb?></a>";
+        let mut p = Parser::new();
+        p.feed_str(doc);
+        let v: Vec<Event> = p.map(|e| e.unwrap()).collect();
+        match v[0] {
+            Event::ElementStart(ref tag) => {
+                assert_eq!(tag.attributes[&("b".to_owned(), None)], "x y z\n")
+            }
+            ref e => panic!("Unexpected event {:?}", e),
+        }
+        assert_eq!(v[1], Event::Characters("1\n2\n3".to_owned()));
+        assert_eq!(v[2], Event::CDATA("\n".to_owned()));
+        assert_eq!(v[3], Event::Comment("\n".to_owned()));
+        assert_eq!(
+            v[4],
+            Event::PI(ProcessingInstruction {
+                target: "pi".to_owned(),
+                data: "a\nb".to_owned(),
+            })
+        );
+
+        let mut p = Parser::new();
+        p.set_normalization(false);
+        p.feed_str(doc);
+        let v: Vec<Event> = p.map(|e| e.unwrap()).collect();
+        match v[0] {
+            Event::ElementStart(ref tag) => {
+                assert_eq!(tag.attributes[&("b".to_owned(), None)], "x\r\ny\tz\n")
+            }
+            ref e => panic!("Unexpected event {:?}", e),
+        }
+        assert_eq!(v[1], Event::Characters("1\r\n2\r3".to_owned()));
+
+        let mut p = Parser::new();
+        p.feed_str("<?xml version='1.1'?><a>1\u{85}2\r\u{85}3\u{2028}</a>");
+        assert_eq!(
+            p.nth(2),
+            Some(Ok(Event::Characters("1\n2\n3\n".to_owned())))
+        );
+        let mut p = Parser::new();
+        p.feed_str("<a>1\u{85}2\u{2028}</a>");
+        assert_eq!(
+            p.nth(1),
+            Some(Ok(Event::Characters("1\u{85}2\u{2028}".to_owned())))
+        );
+    }
+
+    #[test]
+    fn test_line_breaks() {
+        let doc = "<a>\r\n\r\r\n\n<b\r\n/>\u{1}</a>";
+        for chunk_size in 1..doc.len() {
+            let mut p = Parser::new();
+            let mut v = Vec::new();
+            for chunk in doc.as_bytes().chunks(chunk_size) {
+                p.feed_bytes(chunk);
+                v.extend(&mut p);
+            }
+            assert_eq!(
+                v.pop(),
+                Some(Err(ParserError {
+                    line: 6,
+                    col: 3,
+                    offset: 15,
+                    kind: ParserErrorKind::InvalidChar,
+                })),
+            );
+        }
+    }
+
+    #[test]
+    fn test_spans() {
+        let doc = "<?xml version='1.0'?>\n<a x='&amp;'>t&lt;\r\n<b/></a >";
+        let expected = [
+            (0, 21),
+            (21, 22),
+            (22, 35),
+            (35, 42),
+            (42, 46),
+            (42, 46),
+            (46, 51),
+        ];
+        for chunk_size in 1..doc.len() {
+            let mut p = Parser::new();
+            let mut spans = Vec::new();
+            for chunk in doc.as_bytes().chunks(chunk_size) {
+                p.feed_bytes(chunk);
+                while let Some(event) = p.next() {
+                    assert!(event.is_ok());
+                    spans.push(p.span());
+                }
+            }
+            let offsets: Vec<(usize, usize)> = spans
+                .iter()
+                .map(|span| (span.start.offset, span.end.offset))
+                .collect();
+            assert_eq!(offsets, expected);
+            assert_eq!(
+                spans[3],
+                Span {
+                    start: Position {
+                        offset: 35,
+                        line: 2,
+                        col: 14,
+                    },
+                    end: Position {
+                        offset: 42,
+                        line: 3,
+                        col: 1,
+                    },
+                }
+            );
+            assert_eq!((spans[6].start.col, spans[6].end.col), (5, 10));
+        }
+
+        let mut p = BorrowedParser::new("<a>\n<b x=y/></a>");
+        assert!(p.nth(1).unwrap().is_ok());
+        let err = p.next().unwrap().unwrap_err();
+        assert_eq!((err.offset, err.line, err.col), (10, 2, 7));
+        let span = p.span();
+        assert_eq!((span.start.offset, span.end.offset), (4, 10));
+        assert_eq!((span.start.line, span.start.col), (2, 1));
+
+        // Errors use the same 1-based columns as spans, even before any input
+        let mut p = Parser::new();
+        p.set_strict(true);
+        p.finish();
+        let err = p.next().unwrap().unwrap_err();
+        assert_eq!((err.offset, err.line, err.col), (0, 1, 1));
+    }
+
+    #[test]
+    fn test_lenient() {
+        let mut p = Parser::new();
+        p.set_strict(true);
+        p.set_lenient(true);
+        p.feed_str("<a x='1' x='2' p:y='3'>AT&T<b/x><![CDAT[x]]><c><d></c>\u{1}</e><!-- -- -->");
+        p.finish();
+        let events: Vec<String> = p
+            .by_ref()
+            .map(|event| match event.unwrap() {
+                Event::ElementStart(tag) => {
+                    let mut attributes: Vec<String> = tag
+                        .attributes
+                        .iter()
+                        .map(|((name, _), value)| format!(" {}='{}'", name, value))
+                        .collect();
+                    attributes.sort();
+                    format!("<{}{}>", tag.name, attributes.concat())
+                }
+                Event::ElementEnd(tag) => format!("</{}>", tag.name),
+                Event::Characters(text) => text,
+                e => panic!("Unexpected event {:?}", e),
+            })
+            .collect();
+        assert_eq!(
+            events.concat(),
+            "<a x='1'>AT&T<b></b><c><d></d></c>\u{1}</a>"
+        );
+
+        let kinds: Vec<ParserErrorKind> = p.diagnostics().iter().map(|e| e.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ParserErrorKind::DuplicateAttribute,
+                ParserErrorKind::UnboundNsPrefixInAttributeName,
+                ParserErrorKind::InvalidEntity,
+                ParserErrorKind::ExpectedTagClose,
+                ParserErrorKind::InvalidCdataStart,
+                ParserErrorKind::MismatchedEndTag {
+                    expected: "d".to_owned(),
+                    found: "c".to_owned(),
+                },
+                ParserErrorKind::InvalidChar,
+                ParserErrorKind::MismatchedEndTag {
+                    expected: "a".to_owned(),
+                    found: "e".to_owned(),
+                },
+                ParserErrorKind::InvalidCommentContent,
+                ParserErrorKind::UnexpectedEof {
+                    construct: None,
+                    open_elements: vec!["a".to_owned()],
+                },
+            ]
+        );
+        assert_eq!(p.state(), ParserState::BetweenDocuments);
+
+        let mut p = Parser::new();
+        p.set_lenient(true);
+        p.feed_bytes(b"<a>\xFF</a>");
+        assert!(p.next().unwrap().is_ok());
+        assert_eq!(
+            p.next().map(|e| e.map_err(|e| e.kind)),
+            Some(Err(ParserErrorKind::InvalidUtf8))
+        );
+        assert_eq!(p.next(), None);
+    }
+
+    #[test]
+    fn test_config_whitespace() {
+        let doc = "<a>\n  <b> x </b>\r\n</a>";
+        let texts = |config: ParserConfig| -> Vec<String> {
+            let mut p = Parser::with_config(config);
+            p.feed_str(doc);
+            p.filter_map(|e| match e.unwrap() {
+                Event::Characters(text) => Some(text),
+                _ => None,
+            })
+            .collect()
+        };
+        assert_eq!(texts(ParserConfig::new()), vec!["\n  ", " x ", "\n"]);
+        assert_eq!(
+            texts(ParserConfig::new().ignore_whitespace(true)),
+            vec![" x "]
+        );
+        assert_eq!(texts(ParserConfig::new().trim_whitespace(true)), vec!["x"]);
+
+        let config = ParserConfig::new().trim_whitespace(true);
+        let mut p = BorrowedParser::with_config("<a> &lt;x </a>", config);
+        assert!(match p.nth(1) {
+            Some(Ok(BorrowedEvent::Characters(Cow::Owned(ref text)))) => text == "<x",
+            _ => false,
+        });
+        let config = ParserConfig::new().trim_whitespace(true);
+        let mut p = BorrowedParser::with_config("<a> x </a>", config);
+        assert!(match p.nth(1) {
+            Some(Ok(BorrowedEvent::Characters(Cow::Borrowed("x")))) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_config_coalesce() {
+        let doc = "<a>1&amp;<![CDATA[2]]><!--c-->3<?pi?>4</a> ";
+        let config = ParserConfig::new()
+            .coalesce_characters(true)
+            .skip_comments(true);
+        for chunk_size in 1..doc.len() {
+            let mut p = Parser::with_config(config.clone());
+            let mut v = Vec::new();
+            for chunk in doc.as_bytes().chunks(chunk_size) {
+                p.feed_bytes(chunk);
+                v.extend(&mut p);
+            }
+            p.finish();
+            v.extend(&mut p);
+            let v: Vec<Event> = v.into_iter().map(|e| e.unwrap()).collect();
+            assert_eq!(v.len(), 6);
+            assert_eq!(v[1], Event::Characters("1&23".to_owned()));
+            assert_eq!(
+                v[2],
+                Event::PI(ProcessingInstruction {
+                    target: "pi".to_owned(),
+                    data: String::new(),
+                })
+            );
+            assert_eq!(v[3], Event::Characters("4".to_owned()));
+            assert_eq!(v[5], Event::Characters(" ".to_owned()));
+        }
+
+        let config = config.skip_processing_instructions(true);
+        let mut p = Parser::with_config(config.ignore_whitespace(true));
+        p.feed_str(doc);
+        p.finish();
+        let v: Vec<Event> = p.by_ref().map(|e| e.unwrap()).collect();
+        assert_eq!(v.len(), 3);
+        assert_eq!(v[1], Event::Characters("1&234".to_owned()));
+
+        let mut p = Parser::with_config(ParserConfig::new().coalesce_characters(true));
+        p.feed_str(doc);
+        assert_eq!(p.nth(1), Some(Ok(Event::Characters("1&2".to_owned()))));
+        let span = p.span();
+        assert_eq!((span.start.offset, span.end.offset), (3, 22));
+    }
+
+    #[test]
+    fn test_limits() {
+        fn first_error(config: ParserConfig, doc: &str) -> Option<ParserErrorKind> {
+            let mut p = Parser::with_config(config);
+            p.feed_str(doc);
+            p.finish();
+            p.filter_map(|e| e.err()).map(|e| e.kind).next()
+        }
+
+        let doc = "<a x='12345'><b:c y='1' z='2'>text<![CDATA[cdata]]></b:c></a>";
+        let config = ParserConfig::new().lenient(true);
+        assert_eq!(first_error(config.clone(), doc), None);
+        assert_eq!(
+            first_error(config.clone().max_depth(1), doc),
+            Some(ParserErrorKind::NestingTooDeep)
+        );
+        assert_eq!(first_error(config.clone().max_depth(2), doc), None);
+        assert_eq!(
+            first_error(config.clone().max_attributes(1), doc),
+            Some(ParserErrorKind::TooManyAttributes)
+        );
+        assert_eq!(
+            first_error(config.clone().max_name_length(2), doc),
+            Some(ParserErrorKind::NameTooLong)
+        );
+        assert_eq!(first_error(config.clone().max_name_length(3), doc), None);
+        assert_eq!(
+            first_error(config.clone().max_attribute_value_size(4), doc),
+            Some(ParserErrorKind::AttributeValueTooLarge)
+        );
+        assert_eq!(
+            first_error(config.clone().max_text_size(4), doc),
+            Some(ParserErrorKind::TextTooLarge)
+        );
+        assert_eq!(first_error(config.clone().max_text_size(5), doc), None);
+        assert_eq!(
+            first_error(
+                config.clone().coalesce_characters(true).max_text_size(5),
+                doc
+            ),
+            Some(ParserErrorKind::TextTooLarge)
+        );
+        assert_eq!(
+            first_error(config.clone().max_buffer_size(16), doc),
+            Some(ParserErrorKind::InputBufferTooLarge)
+        );
+
+        // Input not parsed yet is buffered too
+        let buffer_error = |size: usize| {
+            let mut p = Parser::with_config(config.clone().max_buffer_size(size));
+            let mut errors = Vec::new();
+            for i in 0..doc.len() {
+                p.feed_str(&doc[i..i + 1]);
+                errors.extend(p.by_ref().filter_map(|e| e.err()));
+            }
+            errors.into_iter().next()
+        };
+        assert_eq!(
+            buffer_error(16).map(|e| e.kind),
+            Some(ParserErrorKind::InputBufferTooLarge)
+        );
+        assert_eq!(buffer_error(17), None);
+
+        let mut p = Parser::with_config(ParserConfig::new().max_buffer_size(8));
+        p.feed_str("<a>text");
+        p.feed_str("<b/>");
+        p.feed_str("</a>");
+        let v: Vec<Result<Event, ParserError>> = p.by_ref().collect();
+        assert_eq!(v.len(), 2);
+        let e = v[1].as_ref().unwrap_err();
+        assert_eq!(
+            (e.kind.clone(), e.offset),
+            (ParserErrorKind::InputBufferTooLarge, 7)
+        );
+
+        // Limits apply to input split across several chunks
+        let mut p = Parser::with_config(ParserConfig::new().max_name_length(4));
+        p.feed_str("<ab");
+        assert!(p.next().is_none());
+        p.feed_str("cde");
+        let e = p.next().unwrap().unwrap_err();
+        assert_eq!(e.kind, ParserErrorKind::NameTooLong);
+        assert!(p.next().is_none());
+    }
+
+    #[test]
+    fn test_namespace_unaware() {
+        let mut p = Parser::with_config(ParserConfig::new().namespace_aware(false));
+        p.feed_str("<soap:a xmlns='urn:x' xmlns:b='urn:b' b:c='1'><b:d/></soap:a>");
+        p.finish();
+
+        let v: Vec<Event> = p.map(|e| e.unwrap()).collect();
+        let mut attr: AttrMap<(String, Option<String>), String> = AttrMap::new();
+        attr.insert(("xmlns".to_owned(), None), "urn:x".to_owned());
+        attr.insert(("xmlns:b".to_owned(), None), "urn:b".to_owned());
+        attr.insert(("b:c".to_owned(), None), "1".to_owned());
+        let start = |name: &str, attributes| {
+            Event::ElementStart(StartTag {
+                name: name.to_owned(),
+                ns: None,
+                prefix: None,
+                attributes,
+                namespaces: Vec::new(),
+            })
+        };
+        let end = |name: &str| {
+            Event::ElementEnd(EndTag {
+                name: name.to_owned(),
+                ns: None,
+                prefix: None,
+            })
+        };
+        assert_eq!(
+            v,
+            vec![
+                start("soap:a", attr),
+                start("b:d", AttrMap::new()),
+                end("b:d"),
+                end("soap:a"),
+            ]
+        );
     }
 
-    // Inside a doctype
-    // '>' after appropriate opening => OutsideTag
-    fn in_doctype(&mut self, c: char) -> Result<Option<Event>, ParserError> {
-        static DOCTYPE_PATTERN: [char; 6] = ['O', 'C', 'T', 'Y', 'P', 'E'];
-        match self.level {
-            0..=5 => {
-                if c == DOCTYPE_PATTERN[self.level as usize] {
-                    self.level += 1;
-                } else {
-                    return self.error(ParserErrorKind::InvalidDoctype);
-                }
-            }
-            6 => {
-                match c {
-                    ' ' | '\t' | '\r' | '\n' => (),
-                    _ => return self.error(ParserErrorKind::InvalidDoctype),
-                }
-                self.level += 1;
-            }
-            _ if c == '>' => {
-                self.level = 0;
-                self.st = State::OutsideTag;
+    #[test]
+    fn test_namespace_declarations() {
+        let doc = "<a xmlns='urn:a' xmlns:b='urn:b' b:c='1'><d xmlns=''/></a>";
+        let mut p = Parser::with_config(ParserConfig::new().strip_namespace_declarations(true));
+        p.feed_str(doc);
+
+        let v: Vec<Event> = p.map(|e| e.unwrap()).collect();
+        let tag = match v[0] {
+            Event::ElementStart(ref tag) => tag,
+            _ => panic!("Expected ElementStart"),
+        };
+        assert_eq!(
+            tag.namespaces,
+            vec![
+                (None, "urn:a".to_owned()),
+                (Some("b".to_owned()), "urn:b".to_owned()),
+            ]
+        );
+        assert_eq!(tag.attributes.len(), 1);
+        assert_eq!(
+            tag.attributes
+                .get(&("c".to_owned(), Some("urn:b".to_owned()))),
+            Some(&"1".to_owned())
+        );
+        match v[1] {
+            Event::ElementStart(ref tag) => {
+                assert_eq!(tag.ns, None);
+                assert_eq!(tag.namespaces, vec![(None, String::new())]);
+                assert!(tag.attributes.is_empty());
             }
-            _ => (),
+            _ => panic!("Expected ElementStart"),
         }
-        Ok(None)
-    }
-}
 
-#[cfg(test)]
-mod parser_tests {
-    use super::Parser;
-    use crate::{AttrMap, EndTag, Event, ParserError, StartTag};
+        let mut p = Parser::new();
+        p.feed_str("<a xmlns:b='urn:b' xmlns:b='urn:c'/>");
+        let e = p.next().unwrap().unwrap_err();
+        assert_eq!(e.kind, ParserErrorKind::DuplicateAttribute);
+    }
 
     #[test]
-    fn test_start_tag() {
+    fn test_namespace_scope() {
         let mut p = Parser::new();
-        let mut i = 0u8;
-        p.feed_str("<a>");
-        for event in p {
-            i += 1;
+        p.feed_str("<a xmlns='urn:a' xmlns:b='urn:b'><c xmlns:b='urn:c' xmlns=''/>text</a>");
+        let xml = "http://www.w3.org/XML/1998/namespace";
+        let xmlns = "http://www.w3.org/2000/xmlns/";
+
+        assert_eq!(p.namespace_for_prefix(Some("b")), None);
+        assert_eq!(
+            p.in_scope_namespaces(),
+            vec![(Some("xml"), xml), (Some("xmlns"), xmlns)]
+        );
+        p.next();
+        assert_eq!(p.namespace_for_prefix(None), Some("urn:a"));
+        assert_eq!(p.namespace_for_prefix(Some("b")), Some("urn:b"));
+        // Inside <c/>, including its ElementEnd
+        for _ in 0..2 {
+            p.next();
+            assert_eq!(p.namespace_for_prefix(None), None);
+            assert_eq!(p.namespace_for_prefix(Some("b")), Some("urn:c"));
             assert_eq!(
-                event,
-                Ok(Event::ElementStart(StartTag {
-                    name: "a".to_owned(),
-                    ns: None,
-                    prefix: None,
-                    attributes: AttrMap::new()
-                })),
+                p.in_scope_namespaces(),
+                vec![
+                    (Some("xml"), xml),
+                    (Some("xmlns"), xmlns),
+                    (Some("b"), "urn:c"),
+                ]
             );
         }
-        assert_eq!(i, 1u8);
+        p.next();
+        assert_eq!(p.namespace_for_prefix(None), Some("urn:a"));
+        assert_eq!(
+            p.in_scope_namespaces(),
+            vec![
+                (Some("xml"), xml),
+                (Some("xmlns"), xmlns),
+                (None, "urn:a"),
+                (Some("b"), "urn:b"),
+            ]
+        );
+        p.next();
+        assert_eq!(p.namespace_for_prefix(Some("b")), Some("urn:b"));
+        assert!(p.next().is_none());
+        assert_eq!(p.namespace_for_prefix(Some("b")), None);
     }
 
     #[test]
-    fn test_end_tag() {
-        let mut p = Parser::new();
-        let mut i = 0u8;
-        p.feed_str("</a>");
-        for event in p {
-            i += 1;
-            assert_eq!(
-                event,
-                Ok(Event::ElementEnd(EndTag {
-                    name: "a".to_owned(),
-                    ns: None,
-                    prefix: None
-                })),
-            );
+    fn test_initial_namespaces() {
+        let config = ParserConfig::new()
+            .define_prefix("stream".to_owned(), "urn:stream".to_owned())
+            .default_ns("urn:client".to_owned());
+        let mut p = Parser::with_config(config);
+        assert_eq!(p.namespace_for_prefix(Some("stream")), Some("urn:stream"));
+        p.feed_str("<stream:features><bind xmlns=''/><x:y/></stream:features>");
+
+        let v: Vec<Result<Event, ParserError>> = p.collect();
+        match v[0] {
+            Ok(Event::ElementStart(ref tag)) => {
+                assert_eq!(tag.ns, Some("urn:stream".to_owned()));
+                assert_eq!(tag.prefix, Some("stream".to_owned()));
+            }
+            _ => panic!("Expected ElementStart"),
         }
-        assert_eq!(i, 1u8);
+        match v[1] {
+            Ok(Event::ElementStart(ref tag)) => assert_eq!(tag.ns, None),
+            _ => panic!("Expected ElementStart"),
+        }
+        assert_eq!(
+            v[3].as_ref().unwrap_err().kind,
+            ParserErrorKind::UnboundNsPrefixInTagName
+        );
     }
 
     #[test]
-    fn test_self_closing_with_space() {
+    fn test_reset() {
         let mut p = Parser::new();
-        p.feed_str("<register />");
+        p.set_strict(true);
+        p.feed_str("<?xml version='1.1'?><stream xmlns:s='urn:s'><proceed/>");
+        let v: Vec<Event> = p.by_ref().map(|e| e.unwrap()).collect();
+        assert_eq!(v.len(), 4);
+        p.feed_str("<?xml version='1.0'?><s:stream/>");
+        p.reset(true);
+        assert!(match p.next() {
+            Some(Ok(Event::XmlDecl(ref decl))) => decl.version == "1.0",
+            _ => false,
+        });
+        // The namespace bindings of the previous document are forgotten
+        assert_eq!(
+            p.next().unwrap().unwrap_err().kind,
+            ParserErrorKind::UnboundNsPrefixInTagName
+        );
+        assert!(p.next().is_none());
 
-        let v: Vec<Result<Event, ParserError>> = p.collect();
+        p.feed_str("<ignored/>");
+        p.reset(false);
+        p.feed_str("<a>&#1;</a>");
+        p.finish();
+        // Input following the error is discarded
+        p.next();
+        assert_eq!(p.span().start.offset, 86);
         assert_eq!(
-            v,
-            vec![
-                Ok(Event::ElementStart(StartTag {
-                    name: "register".to_owned(),
-                    ns: None,
-                    prefix: None,
-                    attributes: AttrMap::new()
-                })),
-                Ok(Event::ElementEnd(EndTag {
-                    name: "register".to_owned(),
-                    ns: None,
-                    prefix: None,
-                }))
-            ],
+            p.next().unwrap().unwrap_err().kind,
+            ParserErrorKind::InvalidChar
         );
     }
 
     #[test]
-    fn test_self_closing_without_space() {
-        let mut p = Parser::new();
-        p.feed_str("<register/>");
+    fn test_concatenated_documents() {
+        let doc = "<?xml version='1.0'?><a/>\n<?xml version='1.0'?>\n<b>x</b><!--c-->";
+        let config = ParserConfig::new()
+            .strict(true)
+            .concatenated_documents(true);
+        for chunk_size in 1..doc.len() {
+            let mut p = Parser::with_config(config.clone());
+            let mut v = Vec::new();
+            for chunk in doc.as_bytes().chunks(chunk_size) {
+                p.feed_bytes(chunk);
+                v.extend(&mut p);
+            }
+            assert_eq!(p.pull(), Ok(Pull::NeedMoreInput));
+            p.finish();
+            v.extend(&mut p);
+            let v: Vec<Event> = v.into_iter().map(|e| e.unwrap()).collect();
+            assert_eq!(v.len(), 12);
+            assert!(match (&v[3], &v[4], &v[5]) {
+                (Event::DocumentEnd, Event::Characters(_), Event::XmlDecl(_)) => true,
+                _ => false,
+            });
+            assert_eq!(v[10], Event::DocumentEnd);
+            assert_eq!(v[11], Event::Comment("c".to_owned()));
+        }
 
-        let v: Vec<Result<Event, ParserError>> = p.collect();
+        // Only whitespace may precede the XML declaration of a following document
+        let mut p = Parser::with_config(config);
+        p.feed_str("<a/><!--c--><?xml version='1.0'?>");
         assert_eq!(
-            v,
-            vec![
-                Ok(Event::ElementStart(StartTag {
-                    name: "register".to_owned(),
-                    ns: None,
-                    prefix: None,
-                    attributes: AttrMap::new()
-                })),
-                Ok(Event::ElementEnd(EndTag {
-                    name: "register".to_owned(),
-                    ns: None,
-                    prefix: None,
-                }))
-            ],
+            p.nth(4).unwrap().unwrap_err().kind,
+            ParserErrorKind::MisplacedXmlDecl
         );
     }
 
     #[test]
-    fn test_self_closing_namespace() {
+    fn test_mismatched_end_tag() {
         let mut p = Parser::new();
-        p.feed_str("<foo:a xmlns:foo='urn:foo'/>");
+        p.feed_str("<a><b></a>");
+        p.finish();
 
         let v: Vec<Result<Event, ParserError>> = p.collect();
-        let mut attr: AttrMap<(String, Option<String>), String> = AttrMap::new();
-        attr.insert(
-            (
-                "foo".to_owned(),
-                Some("http://www.w3.org/2000/xmlns/".to_owned()),
-            ),
-            "urn:foo".to_owned(),
+        assert_eq!(v.len(), 4);
+        assert!(match v[2] {
+            Ok(Event::ElementEnd(ref tag)) => tag.name == "a",
+            _ => false,
+        });
+        // The mismatched end tag did not close `b`
+        assert_eq!(
+            v[3].as_ref().unwrap_err().kind,
+            ParserErrorKind::UnexpectedEof {
+                construct: None,
+                open_elements: vec!["a".to_owned(), "b".to_owned()],
+            }
+        );
+
+        let mut p = Parser::new();
+        p.feed_str("<a><b></a></b></a>");
+        p.finish();
+        assert!(p.all(|e| e.is_ok()));
+
+        let mut p = Parser::new();
+        p.feed_str("<a><b></a></b>");
+        p.finish();
+        assert_eq!(
+            p.last().unwrap().unwrap_err().kind,
+            ParserErrorKind::UnexpectedEof {
+                construct: None,
+                open_elements: vec!["a".to_owned()],
+            }
         );
+    }
+
+    #[test]
+    fn test_lenient_mismatched_end_tag() {
+        let mut p = Parser::new();
+        p.set_lenient(true);
+        p.feed_str("<a>AT&T <b></a>");
+        p.finish();
+
+        let v: Vec<Event> = p.by_ref().map(|e| e.unwrap()).collect();
+        let end = |name: &str| {
+            Event::ElementEnd(EndTag {
+                name: name.to_owned(),
+                ns: None,
+                prefix: None,
+            })
+        };
+        assert_eq!(v.len(), 5);
+        assert_eq!(v[1], Event::Characters("AT&T ".to_owned()));
+        assert_eq!(&v[3..], &[end("b"), end("a")]);
+        let kinds: Vec<ParserErrorKind> = p.diagnostics().iter().map(|e| e.kind.clone()).collect();
         assert_eq!(
-            v,
+            kinds,
             vec![
-                Ok(Event::ElementStart(StartTag {
-                    name: "a".to_owned(),
-                    ns: Some("urn:foo".to_owned()),
-                    prefix: Some("foo".to_owned()),
-                    attributes: attr,
-                })),
-                Ok(Event::ElementEnd(EndTag {
-                    name: "a".to_owned(),
-                    ns: Some("urn:foo".to_owned()),
-                    prefix: Some("foo".to_owned()),
-                }))
-            ],
+                ParserErrorKind::InvalidEntity,
+                ParserErrorKind::MismatchedEndTag {
+                    expected: "b".to_owned(),
+                    found: "a".to_owned(),
+                },
+            ]
         );
     }
 
     #[test]
-    fn test_pi() {
-        let mut p = Parser::new();
-        let mut i = 0u8;
-        p.feed_str("<?xml version='1.0' encoding='utf-8'?>");
-        for event in p {
-            i += 1;
+    fn test_attribute_without_value() {
+        let cases = [
+            ("<a><b c></b>x</a>", 8),
+            ("<a><b c/>x</a>", 8),
+            ("<a><b c >x</b></a>", 9),
+        ];
+        for &(doc, offset) in cases.iter() {
+            let mut p = Parser::new();
+            p.feed_str(doc);
+            assert!(p.next().unwrap().is_ok());
+            let err = p.next().unwrap().unwrap_err();
             assert_eq!(
-                event,
-                Ok(Event::PI("xml version='1.0' encoding='utf-8'".to_owned())),
+                (err.kind, err.offset),
+                (ParserErrorKind::MalformedXml, offset)
             );
         }
-        assert_eq!(i, 1u8);
+
+        // Lenient parsing resumes after the tag
+        let mut p = Parser::new();
+        p.set_lenient(true);
+        p.feed_str("<a><b c></b>x</a>");
+        p.finish();
+        let v: Vec<Event> = p.by_ref().map(|e| e.unwrap()).collect();
+        assert_eq!(v.len(), 3);
+        assert_eq!(v[1], Event::Characters("x".to_owned()));
+        let kinds: Vec<ParserErrorKind> = p.diagnostics().iter().map(|e| e.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ParserErrorKind::MalformedXml,
+                ParserErrorKind::MismatchedEndTag {
+                    expected: "a".to_owned(),
+                    found: "b".to_owned(),
+                },
+            ]
+        );
     }
 
     #[test]
-    fn test_comment() {
-        let mut p = Parser::new();
-        let mut i = 0u8;
-        p.feed_str("<!--Nothing to see-->");
-        for event in p {
-            i += 1;
-            assert_eq!(event, Ok(Event::Comment("Nothing to see".to_owned())));
+    fn test_borrowed_namespaces() {
+        let is_borrowed = |ns: &Option<Cow<str>>, expected: &str| match *ns {
+            Some(Cow::Borrowed(ns)) => ns == expected,
+            _ => false,
+        };
+        let mut p = BorrowedParser::new(
+            "<a xmlns='urn:a' xmlns:x='urn:x' xmlns:y='urn:&#121;'><b x:c='' y:d=''/></a>",
+        );
+        match p.next() {
+            Some(Ok(BorrowedEvent::ElementStart(tag))) => assert!(is_borrowed(&tag.ns, "urn:a")),
+            e => panic!("Unexpected event {:?}", e),
+        }
+        match p.next() {
+            Some(Ok(BorrowedEvent::ElementStart(tag))) => {
+                assert!(is_borrowed(&tag.ns, "urn:a"));
+                for (name, ns) in tag.attributes.keys() {
+                    match &name[..] {
+                        "c" => assert!(is_borrowed(ns, "urn:x")),
+                        // Namespaces containing references are unescaped
+                        _ => assert!(match *ns {
+                            Some(Cow::Owned(ref ns)) => ns == "urn:y",
+                            _ => false,
+                        }),
+                    }
+                }
+            }
+            e => panic!("Unexpected event {:?}", e),
+        }
+        match p.next() {
+            Some(Ok(BorrowedEvent::ElementEnd(tag))) => assert!(is_borrowed(&tag.ns, "urn:a")),
+            e => panic!("Unexpected event {:?}", e),
         }
-        assert_eq!(i, 1u8);
     }
+
     #[test]
-    fn test_cdata() {
+    fn test_byte_order_mark() {
         let mut p = Parser::new();
-        let mut i = 0u8;
-        p.feed_str("<![CDATA[<html><head><title>x</title></head><body/></html>]]>");
-        for event in p {
-            i += 1;
-            assert_eq!(
-                event,
-                Ok(Event::CDATA(
-                    "<html><head><title>x</title></head><body/></html>".to_owned()
-                )),
-            );
-        }
-        assert_eq!(i, 1u8);
+        p.feed_str("\u{feff}<?xml version='1.0'?><a/>");
+        let v: Vec<Event> = p.by_ref().map(|e| e.unwrap()).collect();
+        assert_eq!(v.len(), 3);
+        assert!(match v[0] {
+            Event::XmlDecl(_) => true,
+            _ => false,
+        });
+
+        let mut p = BorrowedParser::new("\u{feff}<a/>");
+        assert!(p.next().unwrap().is_ok());
+        assert_eq!(
+            p.span().start,
+            Position {
+                offset: 3,
+                line: 1,
+                col: 1,
+            }
+        );
+
+        // Elsewhere it is character data
+        let mut p = Parser::new();
+        p.feed_str("<a>\u{feff}</a>");
+        assert_eq!(
+            p.nth(1).unwrap(),
+            Ok(Event::Characters("\u{feff}".to_owned()))
+        );
     }
 
     #[test]
-    fn test_characters() {
+    fn test_entity_normalization() {
+        let doc = "<!DOCTYPE a [<!ENTITY e 'x&#9;y&#10;&f;'><!ENTITY f 'z&#13;'>]>\
+                   <a b='&e;&#9;'>&e;</a>";
         let mut p = Parser::new();
-        let mut i = 0u8;
-        p.feed_str("<text>Hello World, it&apos;s a nice day</text>");
-        for event in p {
-            i += 1;
-            if i == 2 {
-                assert_eq!(
-                    event,
-                    Ok(Event::Characters("Hello World, it's a nice day".to_owned())),
-                );
+        p.feed_str(doc);
+        let v: Vec<Event> = p.skip(1).map(|e| e.unwrap()).collect();
+        match v[0] {
+            Event::ElementStart(ref tag) => {
+                // Character references in the value itself are kept
+                assert_eq!(tag.attributes[&("b".to_owned(), None)], "x y z \t")
             }
+            ref e => panic!("Unexpected event {:?}", e),
         }
-        assert_eq!(i, 3u8);
-    }
+        assert_eq!(v[1], Event::Characters("x\ty\nz\r".to_owned()));
 
-    #[test]
-    fn test_doctype() {
         let mut p = Parser::new();
-        let mut i = 0u8;
-        p.feed_str("<!DOCTYPE html>");
-        for _ in p {
-            i += 1;
+        p.set_normalization(false);
+        p.feed_str(doc);
+        match p.nth(1) {
+            Some(Ok(Event::ElementStart(ref tag))) => {
+                assert_eq!(tag.attributes[&("b".to_owned(), None)], "x\ty\nz\r\t")
+            }
+            e => panic!("Unexpected event {:?}", e),
         }
-        assert_eq!(i, 0u8);
     }
 
     #[test]
-    #[cfg(feature = "ordered_attrs")]
-    fn test_attribute_order() {
-        let input = "<a href='/' title='Home' target='_blank'>";
-        let expected_attributes = vec![
-            (("href".to_owned(), None), "/".to_owned()),
-            (("title".to_owned(), None), "Home".to_owned()),
-            (("target".to_owned(), None), "_blank".to_owned()),
-        ];
+    fn test_initial_namespaces_unaware() {
+        let config = ParserConfig::new()
+            .namespace_aware(false)
+            .define_prefix("x".to_owned(), "urn:x".to_owned())
+            .default_ns("urn:a".to_owned());
+        let mut p = Parser::with_config(config);
+        p.feed_str("<x:a><b/></x:a>");
+        for event in p.by_ref() {
+            match event.unwrap() {
+                Event::ElementStart(StartTag { ns, .. }) | Event::ElementEnd(EndTag { ns, .. }) => {
+                    assert_eq!(ns, None)
+                }
+                e => panic!("Unexpected event {:?}", e),
+            }
+        }
+        assert_eq!(p.namespace_for_prefix(None), None);
+        assert_eq!(p.namespace_for_prefix(Some("x")), None);
+    }
+}
 
-        // Run this 5 times to make it unlikely this test succeeds at random
-        for _ in 0..5 {
+#[cfg(test)]
+#[cfg(feature = "bench")]
+mod parser_bench {
+    extern crate test;
+
+    use self::test::Bencher;
+    use super::{BorrowedParser, Parser};
+
+    fn synthetic_document() -> String {
+        let mut doc = String::from("<?xml version='1.0'?>\n<feed xmlns='urn:test'>\n");
+        for i in 0..2000 {
+            doc.push_str(&format!(
+                "<entry id='{}' type=\"text\"><title>Entry number {}</title>\
+                 <summary>Some text with &amp; an entity, and a longer run of plain \
+                 characters to scan through.</summary><!-- comment {} -->\
+                 <content><![CDATA[<p>Markup</p>]]></content></entry>\n",
+                i, i, i,
+            ));
+        }
+        doc.push_str("</feed>\n");
+        doc
+    }
+
+    fn bench_parse(bh: &mut Bencher, input: &str) {
+        bh.iter(|| {
             let mut p = Parser::new();
             p.feed_str(input);
-            if let Some(Ok(Event::ElementStart(tag))) = p.next() {
-                for (expected, actual) in expected_attributes.iter().zip(tag.attributes) {
-                    assert_eq!(expected, &actual);
-                }
-            } else {
-                panic!("Missing ElementStart event");
+            for event in p {
+                test::black_box(event.unwrap());
             }
-            assert!(p.next().is_none());
-        }
+        });
+        bh.bytes = input.len() as u64;
+    }
+
+    #[bench]
+    fn bench_test01(bh: &mut Bencher) {
+        bench_parse(bh, include_str!("../data/test01.xml"));
+    }
+
+    #[bench]
+    fn bench_test_xmpp(bh: &mut Bencher) {
+        bench_parse(bh, include_str!("../data/test_xmpp.xml"));
+    }
+
+    #[bench]
+    fn bench_synthetic(bh: &mut Bencher) {
+        bench_parse(bh, &synthetic_document());
+    }
+
+    #[bench]
+    fn bench_synthetic_borrowed(bh: &mut Bencher) {
+        let input = synthetic_document();
+        bh.iter(|| {
+            for event in BorrowedParser::new(&input) {
+                test::black_box(event.unwrap());
+            }
+        });
+        bh.bytes = input.len() as u64;
     }
 }
@@ -0,0 +1,282 @@
+// RustyXML
+// Copyright 2013-2016 RustyXML developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::parser::{Event, Parser, ParserError, ParserState, Span};
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+
+// Number of bytes read from the underlying reader at once
+const CHUNK_SIZE: usize = 8192;
+
+#[derive(Debug)]
+/// The structure returned for errors encountered while reading `Event`s with an `EventReader`
+pub enum ReaderError {
+    /// Errors encountered by the `Parser`
+    Parser(ParserError),
+    /// Errors encountered while reading from the underlying reader
+    Io(io::Error),
+}
+
+impl Error for ReaderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ReaderError::Parser(ref err) => Some(err),
+            ReaderError::Io(ref err) => Some(err),
+        }
+    }
+}
+
+impl fmt::Display for ReaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReaderError::Parser(ref err) => err.fmt(f),
+            ReaderError::Io(ref err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl From<ParserError> for ReaderError {
+    fn from(err: ParserError) -> ReaderError {
+        ReaderError::Parser(err)
+    }
+}
+
+impl From<io::Error> for ReaderError {
+    fn from(err: io::Error) -> ReaderError {
+        ReaderError::Io(err)
+    }
+}
+
+/// A streaming XML parser reading its input from an `io::Read`
+///
+/// Input is read in bounded chunks, only once the `Parser` has consumed all previously
+/// read data. Arbitrarily large documents can therefore be parsed in constant memory.
+/// Chunks are kept small enough for the parser's `ParserConfig::max_buffer_size()`.
+/// The input is decoded as described for `Parser::feed_bytes()`.
+/// Once the reader reaches its end `Parser::finish()` is called,
+/// so an incomplete document is reported as an error.
+///
+/// ~~~
+/// use xml::{Event, EventReader};
+///
+/// let input: &[u8] = b"<a href='http://rust-lang.org'>Rust</a>";
+/// for event in EventReader::new(input) {
+///     match event {
+///        Ok(Event::ElementStart(tag)) => println!("<{}>", tag.name),
+///        // [...]
+///        _ => ()
+///     }
+/// }
+/// ~~~
+pub struct EventReader<R> {
+    reader: R,
+    parser: Parser,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> EventReader<R> {
+    /// Returns a new `EventReader` reading from `reader`
+    pub fn new(reader: R) -> EventReader<R> {
+        EventReader::with_parser(Parser::new(), reader)
+    }
+
+    /// Returns a new `EventReader` feeding data read from `reader` to `parser`
+    pub fn with_parser(parser: Parser, reader: R) -> EventReader<R> {
+        EventReader {
+            reader,
+            parser,
+            buf: vec![0; CHUNK_SIZE],
+            done: false,
+        }
+    }
+
+    /// Returns a reference to the underlying reader
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Returns the part of the input the last event was parsed from,
+    /// see `Parser::span()`
+    pub fn span(&self) -> Span {
+        self.parser.span()
+    }
+
+    /// Unwraps this `EventReader`, returning the underlying reader
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Iterator for EventReader<R> {
+    type Item = Result<Event, ReaderError>;
+
+    fn next(&mut self) -> Option<Result<Event, ReaderError>> {
+        loop {
+            if let Some(event) = self.parser.next() {
+                if event.is_err() && self.parser.state() == ParserState::Failed {
+                    // The parser produces no further events, don't read the rest of the input
+                    self.done = true;
+                }
+                return Some(event.map_err(From::from));
+            }
+            if self.done {
+                return None;
+            }
+
+            // Don't read more than the parser can buffer,
+            // allowing for decoding to double the size of the input
+            let len = cmp::min(self.buf.len(), self.parser.buffer_capacity() / 2).max(1);
+            match self.reader.read(&mut self.buf[..len]) {
+                Ok(0) => {
+                    self.done = true;
+                    self.parser.finish();
+                }
+                Ok(len) => self.parser.feed_bytes(&self.buf[..len]),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(ReaderError::Io(err)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod reader_tests {
+    use super::{EventReader, ReaderError, CHUNK_SIZE};
+    use crate::{Event, Parser, ParserConfig, ParserErrorKind};
+    use std::io::{self, Read};
+
+    // Returns its data one byte at a time, then fails if requested
+    struct Trickle<'a> {
+        data: &'a [u8],
+        fail: bool,
+    }
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.data.split_first() {
+                Some((&b, rest)) => {
+                    buf[0] = b;
+                    self.data = rest;
+                    Ok(1)
+                }
+                None if self.fail => Err(io::Error::new(io::ErrorKind::Other, "broken")),
+                None => Ok(0),
+            }
+        }
+    }
+
+    // Counts the bytes handed out from its data
+    struct Counted<'a> {
+        data: &'a [u8],
+        count: usize,
+    }
+
+    impl<'a> Read for Counted<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.data.read(buf)?;
+            self.count += len;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_read_events() {
+        let reader = Trickle {
+            data: "<a>\u{e4}</a>".as_bytes(),
+            fail: false,
+        };
+        let events: Vec<Event> = EventReader::new(reader).map(|e| e.unwrap()).collect();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[1], Event::Characters("\u{e4}".to_owned()));
+    }
+
+    #[test]
+    fn test_io_error() {
+        let reader = Trickle {
+            data: b"<a>",
+            fail: true,
+        };
+        let mut r = EventReader::new(reader);
+        assert!(match r.next() {
+            Some(Ok(Event::ElementStart(_))) => true,
+            _ => false,
+        });
+        assert!(match r.next() {
+            Some(Err(ReaderError::Io(_))) => true,
+            _ => false,
+        });
+        assert!(r.next().is_none());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let input: &[u8] = b"<a><b/>text";
+        let events: Vec<Result<Event, ReaderError>> = EventReader::new(input).collect();
+        assert_eq!(events.len(), 5);
+        assert!(match events[3] {
+            Ok(Event::Characters(ref text)) => text == "text",
+            _ => false,
+        });
+        assert!(match events[4] {
+            Err(ReaderError::Parser(ref err)) => match err.kind {
+                ParserErrorKind::UnexpectedEof {
+                    ref open_elements, ..
+                } => open_elements == &["a"],
+                _ => false,
+            },
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_parser_error() {
+        let input: &[u8] = b"<a b=c>";
+        let mut r = EventReader::new(input);
+        assert!(match r.next() {
+            Some(Err(ReaderError::Parser(err))) =>
+                err.kind == ParserErrorKind::UndelimitedAttribute,
+            _ => false,
+        });
+    }
+    #[test]
+    fn test_stop_after_error() {
+        let mut data = b"<a b=c>".to_vec();
+        data.resize(100 * CHUNK_SIZE, b'x');
+        let mut r = EventReader::new(Counted {
+            data: &data,
+            count: 0,
+        });
+        assert!(match r.next() {
+            Some(Err(ReaderError::Parser(_))) => true,
+            _ => false,
+        });
+        assert!(r.next().is_none());
+        assert!(r.next().is_none());
+        assert_eq!(r.get_ref().count, CHUNK_SIZE);
+    }
+    #[test]
+    fn test_buffer_limit() {
+        let input: &[u8] = b"<a><b>text</b><c x='1'/></a>";
+        let config = ParserConfig::new().max_buffer_size(10);
+        let mut r = EventReader::with_parser(Parser::with_config(config.clone()), input);
+        assert!(r.all(|e| e.is_ok()));
+
+        let r = EventReader::with_parser(Parser::with_config(config.max_buffer_size(9)), input);
+        assert!(match r.last() {
+            Some(Err(ReaderError::Parser(err))) => err.kind == ParserErrorKind::InputBufferTooLarge,
+            _ => false,
+        });
+    }
+}